@@ -0,0 +1,221 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fmt, fs,
+    path::PathBuf,
+    process,
+    sync::{Arc, Mutex},
+};
+
+use crate::{memory::Source, stats::Metric};
+
+/// A cache shared between the fitness threads and the checkpoint reporter.
+pub type SharedCache = Arc<Mutex<Cache>>;
+
+/// FNV-1a, used where a hash has to stay the same between builds.
+pub fn stable_hash(bytes: &[u8]) -> u64 {
+    bytes.iter().fold(0xcbf2_9ce4_8422_2325, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x0100_0000_01b3)
+    })
+}
+
+/// Hash of the config file, or zero without one.
+pub fn config_hash(config: Option<&str>) -> u64 {
+    config
+        .map(|config| stable_hash(&fs::read(config).expect("config file should be readable")))
+        .unwrap_or_default()
+}
+
+/// Everything that affects a measurement. Two runs with the same key are
+/// samples of the same thing.
+#[derive(Debug, Clone)]
+pub struct CacheKey {
+    pub conf: String,
+    pub image: String,
+    pub config_hash: u64,
+    pub experiment_hash: u64,
+    pub seconds: u64,
+    pub payloads: bool,
+    pub source: Source,
+    pub metric: Metric,
+    pub warmup: u64,
+    pub interval: u64,
+    pub stats_interval: Option<u64>,
+}
+
+impl fmt::Display for CacheKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "image={};config={:016x};experiment={:016x};seconds={};payloads={};source={:?};metric={:?};warmup={};interval={};",
+            self.image,
+            self.config_hash,
+            self.experiment_hash,
+            self.seconds,
+            self.payloads,
+            self.source,
+            self.metric,
+            self.warmup,
+            self.interval,
+        )?;
+        // Left out without one, so keys from before it existed still match.
+        if let Some(bytes) = self.stats_interval {
+            write!(f, "stats_interval={bytes};")?;
+        }
+        write!(f, "conf={}", self.conf)
+    }
+}
+
+/// Memory samples from previous runs, persisted so they can be reused by
+/// later `Evolve` and `Run` invocations.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct Cache {
+    entries: HashMap<String, Vec<usize>>,
+
+    #[serde(skip)]
+    path: Option<PathBuf>,
+}
+
+impl Cache {
+    /// Load the cache from the file, starting empty if it doesn't exist yet.
+    /// Every recorded sample is written back to the same file.
+    pub fn load(path: PathBuf) -> Self {
+        let mut cache = match fs::read_to_string(&path) {
+            Ok(contents) => {
+                serde_json::from_str::<Cache>(&contents).expect("cache file should be valid")
+            }
+            Err(_) => Cache::default(),
+        };
+        cache.path = Some(path);
+        cache
+    }
+
+    pub fn samples(&self, key: &CacheKey) -> Option<&[usize]> {
+        self.entries
+            .get(&key.to_string())
+            .map(Vec::as_slice)
+            .filter(|samples| !samples.is_empty())
+    }
+
+    pub fn record(&mut self, key: &CacheKey, sample: usize) {
+        self.entries
+            .entry(key.to_string())
+            .or_default()
+            .push(sample);
+        self.save();
+    }
+
+    /// Add the samples from another cache, keeping whichever side has more
+    /// samples for a key.
+    pub fn merge(&mut self, other: &Cache) {
+        self.absorb(other);
+        self.save();
+    }
+
+    fn absorb(&mut self, other: &Cache) {
+        for (key, samples) in &other.entries {
+            let entry = self.entries.entry(key.clone()).or_default();
+            if entry.len() < samples.len() {
+                *entry = samples.clone();
+            }
+        }
+    }
+
+    /// Write the cache back, merged with what other jemopts sharing the file
+    /// have saved since it was loaded. It's written to a temporary file of
+    /// this process and renamed over the cache, so readers never see half a
+    /// cache.
+    fn save(&mut self) {
+        let Some(path) = self.path.clone() else {
+            return;
+        };
+
+        if let Ok(contents) = fs::read_to_string(&path) {
+            if let Ok(saved) = serde_json::from_str::<Cache>(&contents) {
+                self.absorb(&saved);
+            }
+        }
+
+        let tmp = path.with_extension(format!("{}.tmp", process::id()));
+        fs::write(
+            &tmp,
+            serde_json::to_vec(self).expect("cache should serialize"),
+        )
+        .expect("cache file should be writable");
+        fs::rename(&tmp, &path).expect("cache file should be writable");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::env;
+
+    fn key() -> CacheKey {
+        CacheKey {
+            conf: "narenas:4".to_string(),
+            image: "agent:7".to_string(),
+            config_hash: 1,
+            experiment_hash: 2,
+            seconds: 60,
+            payloads: true,
+            source: Source::Pss,
+            metric: Metric::Final,
+            warmup: 0,
+            interval: 5,
+            stats_interval: None,
+        }
+    }
+
+    fn path(name: &str) -> PathBuf {
+        let path = env::temp_dir().join(format!("jemopt-cache-{name}-{}.json", process::id()));
+        let _ = fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn round_trips_samples_through_the_file() {
+        let path = path("round-trip");
+        let mut cache = Cache::load(path.clone());
+        cache.record(&key(), 100);
+        cache.record(&key(), 200);
+        assert_eq!(cache.samples(&key()), Some(&[100, 200][..]));
+
+        let loaded = Cache::load(path.clone());
+        fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.samples(&key()), Some(&[100, 200][..]));
+    }
+
+    #[test]
+    fn keys_differ_by_source_metric_and_conf() {
+        let mut cache = Cache::default();
+        cache.record(&key(), 100);
+
+        let mut other = key();
+        other.source = Source::Rss;
+        assert_eq!(cache.samples(&other), None);
+        let mut other = key();
+        other.metric = Metric::Peak;
+        assert_eq!(cache.samples(&other), None);
+        let mut other = key();
+        other.conf = "narenas:8".to_string();
+        assert_eq!(cache.samples(&other), None);
+        assert_eq!(cache.samples(&key()), Some(&[100][..]));
+    }
+
+    #[test]
+    fn keeps_what_others_saved_to_the_file() {
+        let path = path("shared");
+        let mut first = Cache::load(path.clone());
+        let mut second = Cache::load(path.clone());
+        first.record(&key(), 100);
+        let mut other = key();
+        other.conf = "narenas:8".to_string();
+        second.record(&other, 200);
+
+        let loaded = Cache::load(path.clone());
+        fs::remove_file(&path).unwrap();
+        assert_eq!(loaded.samples(&key()), Some(&[100][..]));
+        assert_eq!(loaded.samples(&other), Some(&[200][..]));
+    }
+}
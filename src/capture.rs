@@ -0,0 +1,225 @@
+use std::{
+    fs::{self, File},
+    io::{self, LineWriter, Write},
+    path::Path,
+};
+use tokio::{
+    net::UdpSocket,
+    time::{timeout_at, Duration, Instant},
+};
+
+/// A recorded dogstatsd packet.
+#[derive(Debug, Clone)]
+pub struct Packet {
+    /// Seconds into the capture it was sent, if recorded.
+    pub offset: Option<f64>,
+    pub payload: String,
+}
+
+impl Packet {
+    /// A line of a capture, which is the payload with its backslashes
+    /// written as `\\` and its newlines as `\n`, after its offset and a
+    /// space when it has one. Metric names start with a letter, so a plain
+    /// packet log is a capture too.
+    fn parse(line: &str) -> Self {
+        let (offset, payload) = match line.split_once(' ') {
+            Some((offset, payload)) if offset.starts_with(|c: char| c.is_ascii_digit()) => {
+                match offset.parse() {
+                    Ok(offset) => (Some(offset), payload),
+                    Err(_) => (None, line),
+                }
+            }
+            _ => (None, line),
+        };
+        Packet {
+            offset,
+            payload: unescape(payload),
+        }
+    }
+
+    fn line(&self) -> String {
+        let payload = self
+            .payload
+            .trim_end_matches('\n')
+            .replace('\\', "\\\\")
+            .replace('\n', "\\n");
+        match self.offset {
+            Some(offset) => format!("{offset:.6} {payload}"),
+            None => payload,
+        }
+    }
+}
+
+/// Undo the escaping of a line in one pass, so an escaped backslash
+/// before an `n` stays a backslash. Any other backslash is kept as it is.
+fn unescape(payload: &str) -> String {
+    let mut unescaped = String::with_capacity(payload.len());
+    let mut chars = payload.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            unescaped.push(c);
+            continue;
+        }
+        match chars.next() {
+            Some('n') => unescaped.push('\n'),
+            Some('\\') => unescaped.push('\\'),
+            Some(other) => {
+                unescaped.push('\\');
+                unescaped.push(other);
+            }
+            None => unescaped.push('\\'),
+        }
+    }
+    unescaped
+}
+
+/// Read the packets of a capture, skipping blank lines.
+pub fn read(path: &Path) -> io::Result<Vec<Packet>> {
+    Ok(fs::read_to_string(path)?
+        .lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(Packet::parse)
+        .collect())
+}
+
+/// Give each packet without an offset the offset of the one before, or
+/// zero, and sort the packets by offset, as a capture stitched together or
+/// edited by hand can go back in time. Plain packet logs are left alone.
+pub fn order(packets: &mut [Packet]) {
+    if packets.iter().all(|packet| packet.offset.is_none()) {
+        return;
+    }
+
+    let mut offset = 0.0;
+    for packet in packets.iter_mut() {
+        offset = packet.offset.unwrap_or(offset);
+        packet.offset = Some(offset);
+    }
+    // Stable, so packets at the same time keep their order.
+    packets.sort_by(|a, b| a.offset.unwrap().total_cmp(&b.offset.unwrap()));
+}
+
+/// Record every packet sent to the address into the file, with its offset,
+/// for `seconds` or until interrupted. Each packet is written as it arrives
+/// so an interrupted capture is still complete.
+pub async fn capture(listen: &str, output: &Path, seconds: Option<u64>) -> io::Result<()> {
+    let socket = UdpSocket::bind(listen).await?;
+    let mut file = LineWriter::new(File::create(output)?);
+    println!("Capturing from {listen} to {}", output.display());
+
+    let start = Instant::now();
+    let end = seconds.map(|seconds| start + Duration::from_secs(seconds));
+    let mut buf = vec![0; 65536];
+    let mut packets = 0;
+    loop {
+        let received = match end {
+            Some(end) => match timeout_at(end, socket.recv(&mut buf)).await {
+                Ok(received) => received?,
+                Err(_) => break,
+            },
+            None => socket.recv(&mut buf).await?,
+        };
+        let packet = Packet {
+            offset: Some(start.elapsed().as_secs_f64()),
+            payload: String::from_utf8_lossy(&buf[..received]).into_owned(),
+        };
+        writeln!(file, "{}", packet.line())?;
+        packets += 1;
+    }
+    println!("Captured {packets} packets");
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_timed_packets() {
+        let packet = Packet::parse("1.500000 a:1|c\\nb:2|g");
+        assert_eq!(packet.offset, Some(1.5));
+        assert_eq!(packet.payload, "a:1|c\nb:2|g");
+    }
+
+    #[test]
+    fn parses_plain_packets() {
+        let packet = Packet::parse("page.views:1|c|#env:prod");
+        assert_eq!(packet.offset, None);
+        assert_eq!(packet.payload, "page.views:1|c|#env:prod");
+    }
+
+    #[test]
+    fn keeps_spaces_in_plain_packets() {
+        let packet = Packet::parse("_e{5,4}:title|text with spaces");
+        assert_eq!(packet.offset, None);
+        assert_eq!(packet.payload, "_e{5,4}:title|text with spaces");
+    }
+
+    #[test]
+    fn a_bad_offset_is_part_of_the_payload() {
+        let packet = Packet::parse("1x a:1|c");
+        assert_eq!(packet.offset, None);
+        assert_eq!(packet.payload, "1x a:1|c");
+    }
+
+    #[test]
+    fn round_trips_lines() {
+        let packet = Packet {
+            offset: Some(0.25),
+            payload: "a:1|c\nb:2|g\n".to_string(),
+        };
+        let parsed = Packet::parse(&packet.line());
+        assert_eq!(packet.line(), "0.250000 a:1|c\\nb:2|g");
+        assert_eq!(parsed.offset, Some(0.25));
+        assert_eq!(parsed.payload, "a:1|c\nb:2|g");
+    }
+
+    #[test]
+    fn round_trips_escaped_newlines_in_events() {
+        let packet = Packet {
+            offset: Some(1.0),
+            payload: "_e{5,12}:title|line1\\nline2\na:1|c".to_string(),
+        };
+        assert_eq!(
+            packet.line(),
+            "1.000000 _e{5,12}:title|line1\\\\nline2\\na:1|c"
+        );
+        let parsed = Packet::parse(&packet.line());
+        assert_eq!(parsed.payload, packet.payload);
+    }
+
+    #[test]
+    fn keeps_lone_backslashes() {
+        let packet = Packet::parse("a:1|c|#path:C:\\dir\\");
+        assert_eq!(packet.payload, "a:1|c|#path:C:\\dir\\");
+    }
+
+    #[test]
+    fn orders_packets_by_offset() {
+        let mut packets =
+            ["2.0 a:1|c", "b:1|c", "1.0 c:1|c", "1.0 d:1|c", "3.5 e:1|c"].map(Packet::parse);
+        order(&mut packets);
+        let ordered = packets
+            .iter()
+            .map(|packet| (packet.offset.unwrap(), packet.payload.as_str()))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            ordered,
+            [
+                (1.0, "c:1|c"),
+                (1.0, "d:1|c"),
+                (2.0, "a:1|c"),
+                (2.0, "b:1|c"),
+                (3.5, "e:1|c")
+            ]
+        );
+    }
+
+    #[test]
+    fn leaves_plain_packets_alone() {
+        let mut packets = ["b:1|c", "a:1|c"].map(Packet::parse);
+        order(&mut packets);
+        assert_eq!(packets[0].payload, "b:1|c");
+        assert_eq!(packets[1].offset, None);
+    }
+}
@@ -0,0 +1,383 @@
+use genetic_algorithm::chromosome::GenesOwner;
+use genetic_algorithm::extension::Extension;
+use genetic_algorithm::strategy::evolve::prelude::*;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::{fs, path::PathBuf};
+
+use crate::{
+    cache::{Cache, SharedCache},
+    genome::Genome,
+};
+
+/// Everything needed to carry on an interrupted evolution.
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct Checkpoint {
+    pub generation: usize,
+    pub stale_generations: usize,
+    pub best_generation: usize,
+    pub best_genes: Option<Vec<usize>>,
+    pub best_fitness_score: Option<FitnessValue>,
+    pub population: Vec<Vec<usize>>,
+    pub cache: Cache,
+}
+
+impl Checkpoint {
+    pub fn load(path: &str) -> Self {
+        let contents = fs::read_to_string(path).expect("state file should be readable");
+        serde_json::from_str(&contents).expect("state file should be valid")
+    }
+
+    /// Check every saved gene vector decodes with the genome, which fails
+    /// when the schema or layout changed since the checkpoint.
+    pub fn check(&self, genome: &Genome) -> Result<(), String> {
+        for genes in self.population.iter().chain(&self.best_genes) {
+            genome
+                .confs(genes)
+                .map_err(|err| format!("genes {genes:?}: {err}"))?;
+        }
+
+        Ok(())
+    }
+
+    /// Write the checkpoint via a temporary file so a crash mid write
+    /// leaves the previous checkpoint intact.
+    pub fn save(&self, path: &PathBuf) {
+        let tmp = path.with_extension("tmp");
+        fs::write(
+            &tmp,
+            serde_json::to_vec(self).expect("checkpoint should serialize"),
+        )
+        .expect("state file should be writable");
+        fs::rename(&tmp, path).expect("state file should be writable");
+    }
+
+    /// Restore the counters and best chromosome into a freshly built
+    /// evolve. The population itself is restored by [`Resume`], from the
+    /// seed genes.
+    pub fn restore(
+        &self,
+        state: &mut EvolveState<MultiListGenotype<usize>>,
+        genotype: &mut MultiListGenotype<usize>,
+    ) {
+        state.current_generation = self.generation;
+        // Evaluating the seeded population adds one before the first
+        // generation, which [`Resume`] takes back.
+        state.stale_generations = self.stale_generations.saturating_sub(1);
+        state.best_generation = self.best_generation;
+        if let Some(genes) = &self.best_genes {
+            let mut best = MultiListChromosome::new(genes.clone());
+            best.set_fitness_score(self.best_fitness_score);
+            genotype.save_best_genes(&best);
+            state.best_fitness_score = self.best_fitness_score;
+        }
+    }
+}
+
+/// Puts the checkpointed population back exactly at the first generation
+/// after resuming. The seed genes only seed the first population by picking
+/// from them at random, repeats and all, so that population is replaced.
+#[derive(Clone, Debug, Default)]
+pub struct Resume {
+    /// The stale generations when checkpointed, until restored.
+    stale_generations: Option<usize>,
+}
+
+impl Resume {
+    pub fn new(checkpoint: Option<&Checkpoint>) -> Self {
+        Self {
+            stale_generations: checkpoint.map(|checkpoint| checkpoint.stale_generations),
+        }
+    }
+}
+
+impl Extension for Resume {
+    fn call<G: EvolveGenotype, R: Rng, SR: StrategyReporter<Genotype = G>>(
+        &mut self,
+        genotype: &mut G,
+        state: &mut EvolveState<G>,
+        _config: &EvolveConfig,
+        _reporter: &mut SR,
+        rng: &mut R,
+    ) {
+        let Some(stale_generations) = self.stale_generations.take() else {
+            return;
+        };
+
+        genotype.chromosome_destructor_truncate(&mut state.population.chromosomes, 0);
+        // With a single seed the random constructor builds exactly it.
+        for genes in genotype.seed_genes_list().clone() {
+            genotype.set_seed_genes_list(vec![genes]);
+            let chromosome = genotype.chromosome_constructor_random(rng);
+            state.population.chromosomes.push(chromosome);
+        }
+        genotype.set_seed_genes_list(Vec::new());
+        state.stale_generations = stale_generations;
+    }
+}
+
+/// Reports like the simple reporter, and writes a checkpoint at the start
+/// of every generation.
+#[derive(Clone)]
+pub struct CheckpointReporter {
+    inner: EvolveReporterSimple<MultiListGenotype<usize>>,
+    path: PathBuf,
+    cache: SharedCache,
+    /// The first generation after resuming starts from the seeded population
+    /// until [`Resume`] restores it, so isn't checkpointed.
+    resuming: bool,
+}
+
+impl CheckpointReporter {
+    pub fn new(
+        inner: EvolveReporterSimple<MultiListGenotype<usize>>,
+        path: PathBuf,
+        cache: SharedCache,
+        resuming: bool,
+    ) -> Self {
+        Self {
+            inner,
+            path,
+            cache,
+            resuming,
+        }
+    }
+}
+
+impl StrategyReporter for CheckpointReporter {
+    type Genotype = MultiListGenotype<usize>;
+
+    fn flush(&mut self, output: &mut Vec<u8>) {
+        self.inner.flush(output);
+    }
+
+    fn on_enter<S: StrategyState<Self::Genotype>, C: StrategyConfig>(
+        &mut self,
+        genotype: &Self::Genotype,
+        state: &S,
+        config: &C,
+    ) {
+        self.inner.on_enter(genotype, state, config);
+    }
+
+    fn on_exit<S: StrategyState<Self::Genotype>, C: StrategyConfig>(
+        &mut self,
+        genotype: &Self::Genotype,
+        state: &S,
+        config: &C,
+    ) {
+        self.inner.on_exit(genotype, state, config);
+    }
+
+    fn on_start<S: StrategyState<Self::Genotype>, C: StrategyConfig>(
+        &mut self,
+        genotype: &Self::Genotype,
+        state: &S,
+        config: &C,
+    ) {
+        self.inner.on_start(genotype, state, config);
+    }
+
+    fn on_finish<S: StrategyState<Self::Genotype>, C: StrategyConfig>(
+        &mut self,
+        genotype: &Self::Genotype,
+        state: &S,
+        config: &C,
+    ) {
+        self.inner.on_finish(genotype, state, config);
+    }
+
+    fn on_new_generation<S: StrategyState<Self::Genotype>, C: StrategyConfig>(
+        &mut self,
+        genotype: &Self::Genotype,
+        state: &S,
+        config: &C,
+    ) {
+        self.inner.on_new_generation(genotype, state, config);
+        if std::mem::take(&mut self.resuming) {
+            return;
+        }
+
+        let checkpoint = Checkpoint {
+            // The generation has only just started, so the last complete one
+            // is the one before.
+            generation: state.current_generation().saturating_sub(1),
+            stale_generations: state.stale_generations(),
+            best_generation: state.best_generation(),
+            best_genes: state
+                .best_fitness_score()
+                .map(|_| genotype.best_genes().clone()),
+            best_fitness_score: state.best_fitness_score(),
+            population: state
+                .population_as_ref()
+                .chromosomes
+                .iter()
+                .map(|chromosome| chromosome.genes.clone())
+                .collect(),
+            cache: self.cache.lock().unwrap().clone(),
+        };
+        checkpoint.save(&self.path);
+    }
+
+    fn on_new_best_chromosome<S: StrategyState<Self::Genotype>, C: StrategyConfig>(
+        &mut self,
+        genotype: &Self::Genotype,
+        state: &S,
+        config: &C,
+    ) {
+        self.inner.on_new_best_chromosome(genotype, state, config);
+    }
+
+    fn on_new_best_chromosome_equal_fitness<S: StrategyState<Self::Genotype>, C: StrategyConfig>(
+        &mut self,
+        genotype: &Self::Genotype,
+        state: &S,
+        config: &C,
+    ) {
+        self.inner
+            .on_new_best_chromosome_equal_fitness(genotype, state, config);
+    }
+
+    fn on_extension_event<S: StrategyState<Self::Genotype>, C: StrategyConfig>(
+        &mut self,
+        event: ExtensionEvent,
+        genotype: &Self::Genotype,
+        state: &S,
+        config: &C,
+    ) {
+        self.inner
+            .on_extension_event(event, genotype, state, config);
+    }
+
+    fn on_mutate_event<S: StrategyState<Self::Genotype>, C: StrategyConfig>(
+        &mut self,
+        event: MutateEvent,
+        genotype: &Self::Genotype,
+        state: &S,
+        config: &C,
+    ) {
+        self.inner.on_mutate_event(event, genotype, state, config);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::schema::Schema;
+    use std::{env, process};
+
+    /// Scores the genes by their sum, so the evolution needs no workload.
+    #[derive(Clone, Debug)]
+    struct Sum;
+
+    impl Fitness for Sum {
+        type Genotype = MultiListGenotype<usize>;
+        fn calculate_for_chromosome(
+            &mut self,
+            chromosome: &FitnessChromosome<Self>,
+            _genotype: &Self::Genotype,
+        ) -> Option<FitnessValue> {
+            Some(chromosome.genes.iter().sum::<usize>() as FitnessValue)
+        }
+    }
+
+    fn genome() -> Genome {
+        let schema = Schema::parse(
+            r#"
+            [[option]]
+            name = "tcache"
+            type = "bool"
+
+            [[option]]
+            name = "narenas"
+            type = "int"
+            min = 1
+            max = 8
+            "#,
+        )
+        .unwrap();
+        Genome::global(schema)
+    }
+
+    fn checkpoint() -> Checkpoint {
+        Checkpoint {
+            generation: 7,
+            stale_generations: 3,
+            best_generation: 5,
+            best_genes: Some(vec![0, 1]),
+            best_fitness_score: Some(1),
+            population: vec![vec![1, 7], vec![0, 1], vec![1, 3], vec![1, 3], vec![0, 5]],
+            cache: Cache::default(),
+        }
+    }
+
+    #[test]
+    fn resumes_the_saved_population_and_best_genes() {
+        let path = env::temp_dir().join(format!("jemopt-checkpoint-{}.json", process::id()));
+        checkpoint().save(&path);
+        let resume = Checkpoint::load(path.to_str().unwrap());
+        fs::remove_file(&path).unwrap();
+        resume.check(&genome()).unwrap();
+
+        let mut genotype = MultiListGenotype::builder()
+            .with_allele_lists(genome().allele_lists())
+            .build()
+            .unwrap();
+        genotype.set_seed_genes_list(resume.population.clone());
+        let mut evolve = Evolve::builder()
+            .with_genotype(genotype)
+            .with_target_population_size(resume.population.len())
+            .with_max_stale_generations(50)
+            .with_fitness(Sum)
+            .with_fitness_ordering(FitnessOrdering::Minimize)
+            .with_mutate(MutateSingleGene::new(0.2))
+            .with_crossover(CrossoverClone::new())
+            .with_select(SelectElite::new(0.9))
+            .with_extension(Resume::new(Some(&resume)))
+            .build()
+            .unwrap();
+        resume.restore(&mut evolve.state, &mut evolve.genotype);
+
+        // What evolving does up to the extension in the first generation.
+        evolve.setup(None);
+        evolve.state.current_generation += 1;
+        evolve.plugins.extension.call(
+            &mut evolve.genotype,
+            &mut evolve.state,
+            &evolve.config,
+            &mut evolve.reporter,
+            &mut evolve.rng,
+        );
+
+        let population = evolve
+            .state
+            .population
+            .chromosomes
+            .iter()
+            .map(|chromosome| chromosome.genes.clone())
+            .collect::<Vec<_>>();
+        assert_eq!(population, checkpoint().population);
+        assert_eq!(evolve.state.current_generation, 8);
+        assert_eq!(evolve.state.stale_generations, 3);
+        assert_eq!(evolve.state.best_generation, 5);
+        assert_eq!(evolve.state.best_fitness_score, Some(1));
+        assert_eq!(evolve.genotype.best_genes(), &vec![0, 1]);
+    }
+
+    #[test]
+    fn rejects_genes_out_of_range_for_the_schema() {
+        let mut broken = checkpoint();
+        broken.population[2] = vec![1, 8];
+        let err = broken.check(&genome()).unwrap_err();
+        assert!(err.contains("column 2"), "{err}");
+
+        let mut broken = checkpoint();
+        broken.best_genes = Some(vec![2, 0]);
+        assert!(broken.check(&genome()).is_err());
+
+        let mut broken = checkpoint();
+        broken.population[0] = vec![1, 7, 0];
+        let err = broken.check(&genome()).unwrap_err();
+        assert!(err.contains("expected 2 genes"), "{err}");
+    }
+}
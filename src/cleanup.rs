@@ -0,0 +1,259 @@
+use bollard::{
+    container::{ListContainersOptions, RemoveContainerOptions},
+    Docker,
+};
+use std::{
+    cell::Cell,
+    collections::{BTreeSet, HashMap},
+    fs,
+    future::{poll_fn, Future},
+    path::PathBuf,
+    process::{self, Command},
+    sync::Mutex,
+    thread,
+    time::Duration,
+};
+use tokio::signal::unix::{signal, SignalKind};
+
+/// The label on every container jemopt creates, with the creating pid as
+/// its value.
+pub const LABEL: &str = "jemopt";
+
+/// Something started for a run that has to be torn down afterwards.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Resource {
+    Container(String),
+    ProcessGroup(u32),
+    Cgroup(PathBuf),
+}
+
+/// Everything currently started by this process.
+static LIVE: Mutex<BTreeSet<Resource>> = Mutex::new(BTreeSet::new());
+
+/// Keeps a resource registered while it's in use. Dropping the guard tears
+/// the resource down, unless it was released after a clean stop, so
+/// panics, timeouts and errors don't leave anything running.
+#[derive(Debug)]
+pub struct Guard {
+    resource: Resource,
+}
+
+impl Guard {
+    pub fn new(resource: Resource) -> Self {
+        LIVE.lock().unwrap().insert(resource.clone());
+        Self { resource }
+    }
+
+    /// The resource was stopped cleanly, so there's nothing to tear down.
+    pub fn release(self) {
+        LIVE.lock().unwrap().remove(&self.resource);
+    }
+}
+
+impl Drop for Guard {
+    fn drop(&mut self) {
+        if LIVE.lock().unwrap().remove(&self.resource) {
+            teardown(vec![self.resource.clone()]);
+        }
+    }
+}
+
+/// Tear down everything still registered.
+pub fn teardown_all() {
+    let live = std::mem::take(&mut *LIVE.lock().unwrap());
+    teardown(live.into_iter().collect());
+}
+
+/// Forcibly remove the resources. Blocks, and is safe to call from within
+/// the async runtime as the docker calls get a runtime of their own.
+fn teardown(resources: Vec<Resource>) {
+    let mut containers = Vec::new();
+    for resource in resources {
+        match resource {
+            Resource::Container(name) => containers.push(name),
+            Resource::ProcessGroup(pid) => {
+                let _ = Command::new("kill")
+                    .args(["-KILL", "--", &format!("-{pid}")])
+                    .status();
+            }
+            Resource::Cgroup(path) => {
+                let _ = fs::write(path.join("cgroup.kill"), "1");
+                // Killed processes take a moment to leave the cgroup.
+                for _ in 0..50 {
+                    if fs::remove_dir(&path).is_ok() {
+                        break;
+                    }
+                    thread::sleep(Duration::from_millis(100));
+                }
+            }
+        }
+    }
+
+    if containers.is_empty() {
+        return;
+    }
+    let _ = thread::spawn(move || {
+        tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap()
+            .block_on(remove_containers(&containers))
+    })
+    .join();
+}
+
+async fn remove_containers(names: &[String]) {
+    let Ok(docker) = Docker::connect_with_socket_defaults() else {
+        return;
+    };
+    for name in names {
+        println!("Removing container {name}");
+        let _ = docker
+            .remove_container(
+                name,
+                Some(RemoveContainerOptions {
+                    force: true,
+                    ..Default::default()
+                }),
+            )
+            .await;
+    }
+}
+
+thread_local! {
+    /// Whether a panic on this thread is caught, so it won't end jemopt.
+    static CAUGHT: Cell<bool> = const { Cell::new(false) };
+}
+
+/// Puts back whether panics are caught, even when unwinding.
+struct Restore(bool);
+
+impl Drop for Restore {
+    fn drop(&mut self) {
+        CAUGHT.set(self.0);
+    }
+}
+
+/// The future, for a task whose panics are handled where it's joined. They
+/// don't tear everything down, as other runs carry on.
+pub fn caught<F: Future>(future: F) -> impl Future<Output = F::Output> {
+    let mut future = Box::pin(future);
+    poll_fn(move |cx| {
+        let _restore = Restore(CAUGHT.replace(true));
+        future.as_mut().poll(cx)
+    })
+}
+
+/// Tear everything down on a panic, Ctrl-C or SIGTERM.
+pub fn install() {
+    let hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        hook(info);
+        if !CAUGHT.get() {
+            teardown_all();
+        }
+    }));
+
+    thread::spawn(|| {
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .enable_all()
+            .build()
+            .unwrap();
+        runtime.block_on(async {
+            let mut terminate = signal(SignalKind::terminate()).unwrap();
+            tokio::select! {
+                _ = tokio::signal::ctrl_c() => {}
+                _ = terminate.recv() => {}
+            }
+        });
+        println!("Interrupted, cleaning up");
+        teardown_all();
+        process::exit(130);
+    });
+}
+
+/// Whether the jemopt that made a container is still running, from the pid
+/// in its label. A reused pid keeps the container too, which errs on the
+/// side of leaving a live run alone.
+fn creator_alive(labels: Option<&HashMap<String, String>>) -> bool {
+    labels
+        .and_then(|labels| labels.get(LABEL))
+        .and_then(|pid| pid.parse::<u32>().ok())
+        .is_some_and(|pid| PathBuf::from(format!("/proc/{pid}")).exists())
+}
+
+/// Remove the jemopt containers left by invocations that didn't get to
+/// clean up. Those of jemopts still running are kept, unless `all`.
+pub async fn cleanup(all: bool) {
+    let containers = match Docker::connect_with_socket_defaults() {
+        Ok(docker) => {
+            docker
+                .list_containers(Some(ListContainersOptions {
+                    all: true,
+                    filters: HashMap::from([("label", vec![LABEL])]),
+                    ..Default::default()
+                }))
+                .await
+        }
+        Err(err) => Err(err),
+    };
+    let containers = match containers {
+        Ok(containers) => containers,
+        Err(err) => {
+            println!("Failed to list containers: {err}");
+            return;
+        }
+    };
+
+    let (live, leftover): (Vec<_>, Vec<_>) = containers
+        .iter()
+        .partition(|container| !all && creator_alive(container.labels.as_ref()));
+    if !live.is_empty() {
+        println!(
+            "Keeping {} containers of jemopts still running, --all removes them",
+            live.len()
+        );
+    }
+    let names = leftover
+        .iter()
+        .filter_map(|container| container.names.as_ref()?.first())
+        .map(|name| name.trim_start_matches('/').to_string())
+        .collect::<Vec<_>>();
+    if names.is_empty() {
+        println!("No jemopt containers to remove");
+        return;
+    }
+    remove_containers(&names).await;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn labels(pid: &str) -> HashMap<String, String> {
+        HashMap::from([(LABEL.to_string(), pid.to_string())])
+    }
+
+    #[test]
+    fn keeps_containers_of_running_jemopts() {
+        assert!(creator_alive(Some(&labels(&process::id().to_string()))));
+    }
+
+    #[test]
+    fn removes_leftovers() {
+        // Above the largest pid Linux hands out.
+        assert!(!creator_alive(Some(&labels("4194305"))));
+        assert!(!creator_alive(Some(&labels(""))));
+        assert!(!creator_alive(None));
+    }
+
+    #[tokio::test]
+    async fn catches_panics_only_within_the_task() {
+        let task = tokio::spawn(caught(async {
+            assert!(CAUGHT.get());
+            panic!("the load failed");
+        }));
+        assert!(task.await.unwrap_err().is_panic());
+        assert!(!CAUGHT.get());
+    }
+}
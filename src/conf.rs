@@ -0,0 +1,510 @@
+use serde::Deserialize;
+use std::{fmt, str::FromStr};
+
+/// A single value for a jemalloc option.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(untagged)]
+pub enum Value {
+    Int(i64),
+    Bool(bool),
+    Str(String),
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Int(value) => write!(f, "{value}"),
+            Value::Bool(value) => write!(f, "{value}"),
+            Value::Str(value) => write!(f, "{value}"),
+        }
+    }
+}
+
+/// A set of jemalloc options, in the order they are rendered into `MALLOC_CONF`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MallocConf {
+    pub options: Vec<(String, Value)>,
+}
+
+impl MallocConf {
+    pub fn get(&self, name: &str) -> Option<&Value> {
+        self.options
+            .iter()
+            .find(|(option, _)| option == name)
+            .map(|(_, value)| value)
+    }
+}
+
+impl fmt::Display for MallocConf {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (idx, (name, value)) in self.options.iter().enumerate() {
+            if idx > 0 {
+                write!(f, ",")?;
+            }
+            write!(f, "{name}:{value}")?;
+        }
+
+        Ok(())
+    }
+}
+
+/// The confs for one run. `global` applies to every process, and each
+/// override replaces it for the named process.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RunConf {
+    pub global: String,
+    pub overrides: Vec<(String, String)>,
+}
+
+impl RunConf {
+    /// No conf at all, so jemalloc isn't preloaded.
+    pub fn is_empty(&self) -> bool {
+        self.global.is_empty() && self.overrides.iter().all(|(_, conf)| conf.is_empty())
+    }
+}
+
+impl fmt::Display for RunConf {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.global)?;
+        for (process, conf) in &self.overrides {
+            write!(f, ";{process}={conf}")?;
+        }
+
+        Ok(())
+    }
+}
+
+/// What a jemalloc option accepts.
+enum Kind {
+    Bool,
+    Int {
+        min: i64,
+        max: i64,
+    },
+    Choice(&'static [&'static str]),
+    /// Free form strings such as `stats_print_opts`.
+    Text,
+}
+
+const UNBOUNDED: i64 = i64::MAX;
+
+/// The options jemalloc 5.3 understands in `MALLOC_CONF`.
+const KNOWN_OPTIONS: &[(&str, Kind)] = &[
+    ("abort", Kind::Bool),
+    ("abort_conf", Kind::Bool),
+    ("background_thread", Kind::Bool),
+    ("bin_shards", Kind::Text),
+    ("cache_oblivious", Kind::Bool),
+    ("confirm_conf", Kind::Bool),
+    (
+        "dirty_decay_ms",
+        Kind::Int {
+            min: -1,
+            max: UNBOUNDED,
+        },
+    ),
+    ("dss", Kind::Choice(&["disabled", "primary", "secondary"])),
+    ("experimental_infallible_new", Kind::Bool),
+    ("hpa", Kind::Bool),
+    ("hpa_dirty_mult", Kind::Text),
+    (
+        "hpa_hugification_threshold",
+        Kind::Int {
+            min: 0,
+            max: UNBOUNDED,
+        },
+    ),
+    (
+        "hpa_hugify_delay_ms",
+        Kind::Int {
+            min: 0,
+            max: UNBOUNDED,
+        },
+    ),
+    (
+        "hpa_min_purge_interval_ms",
+        Kind::Int {
+            min: 0,
+            max: UNBOUNDED,
+        },
+    ),
+    (
+        "hpa_sec_batch_fill_extra",
+        Kind::Int {
+            min: 0,
+            max: UNBOUNDED,
+        },
+    ),
+    (
+        "hpa_sec_bytes_after_flush",
+        Kind::Int {
+            min: 0,
+            max: UNBOUNDED,
+        },
+    ),
+    (
+        "hpa_sec_max_alloc",
+        Kind::Int {
+            min: 0,
+            max: UNBOUNDED,
+        },
+    ),
+    (
+        "hpa_sec_max_bytes",
+        Kind::Int {
+            min: 0,
+            max: UNBOUNDED,
+        },
+    ),
+    (
+        "hpa_sec_nshards",
+        Kind::Int {
+            min: 0,
+            max: UNBOUNDED,
+        },
+    ),
+    (
+        "hpa_slab_max_alloc",
+        Kind::Int {
+            min: 0,
+            max: UNBOUNDED,
+        },
+    ),
+    ("junk", Kind::Choice(&["true", "false", "alloc", "free"])),
+    ("lg_extent_max_active_fit", Kind::Int { min: 0, max: 63 }),
+    ("lg_prof_interval", Kind::Int { min: -1, max: 63 }),
+    ("lg_prof_sample", Kind::Int { min: 0, max: 63 }),
+    ("lg_san_uaf_align", Kind::Int { min: -1, max: 63 }),
+    ("lg_tcache_flush_large_div", Kind::Int { min: 1, max: 16 }),
+    ("lg_tcache_flush_small_div", Kind::Int { min: 1, max: 16 }),
+    ("lg_tcache_max", Kind::Int { min: 0, max: 23 }),
+    ("lg_tcache_nslots_mul", Kind::Int { min: -16, max: 16 }),
+    (
+        "max_background_threads",
+        Kind::Int {
+            min: 1,
+            max: UNBOUNDED,
+        },
+    ),
+    (
+        "metadata_thp",
+        Kind::Choice(&["disabled", "auto", "always"]),
+    ),
+    (
+        "muzzy_decay_ms",
+        Kind::Int {
+            min: -1,
+            max: UNBOUNDED,
+        },
+    ),
+    (
+        "mutex_max_spin",
+        Kind::Int {
+            min: -1,
+            max: UNBOUNDED,
+        },
+    ),
+    (
+        "narenas",
+        Kind::Int {
+            min: 1,
+            max: u32::MAX as i64,
+        },
+    ),
+    (
+        "oversize_threshold",
+        Kind::Int {
+            min: 0,
+            max: UNBOUNDED,
+        },
+    ),
+    (
+        "percpu_arena",
+        Kind::Choice(&["disabled", "percpu", "phycpu"]),
+    ),
+    ("prof", Kind::Bool),
+    ("prof_accum", Kind::Bool),
+    ("prof_active", Kind::Bool),
+    ("prof_final", Kind::Bool),
+    ("prof_gdump", Kind::Bool),
+    ("prof_leak", Kind::Bool),
+    ("prof_leak_error", Kind::Bool),
+    ("prof_prefix", Kind::Text),
+    (
+        "prof_recent_alloc_max",
+        Kind::Int {
+            min: -1,
+            max: UNBOUNDED,
+        },
+    ),
+    ("prof_stats", Kind::Bool),
+    ("prof_sys_thread_name", Kind::Bool),
+    ("prof_thread_active_init", Kind::Bool),
+    ("prof_time_resolution", Kind::Choice(&["default", "high"])),
+    ("prof_unbias", Kind::Bool),
+    ("retain", Kind::Bool),
+    (
+        "san_guard_large",
+        Kind::Int {
+            min: 0,
+            max: UNBOUNDED,
+        },
+    ),
+    (
+        "san_guard_small",
+        Kind::Int {
+            min: 0,
+            max: UNBOUNDED,
+        },
+    ),
+    ("slab_sizes", Kind::Text),
+    (
+        "stats_interval",
+        Kind::Int {
+            min: -1,
+            max: UNBOUNDED,
+        },
+    ),
+    ("stats_interval_opts", Kind::Text),
+    ("stats_print", Kind::Bool),
+    ("stats_print_opts", Kind::Text),
+    ("tcache", Kind::Bool),
+    (
+        "tcache_gc_delay_bytes",
+        Kind::Int {
+            min: 0,
+            max: UNBOUNDED,
+        },
+    ),
+    (
+        "tcache_gc_incr_bytes",
+        Kind::Int {
+            min: 1,
+            max: UNBOUNDED,
+        },
+    ),
+    (
+        "tcache_max",
+        Kind::Int {
+            min: 0,
+            max: 8 << 20,
+        },
+    ),
+    ("tcache_nslots_large", Kind::Int { min: 1, max: 2048 }),
+    ("tcache_nslots_small_max", Kind::Int { min: 1, max: 2048 }),
+    ("tcache_nslots_small_min", Kind::Int { min: 1, max: 2048 }),
+    ("thp", Kind::Choice(&["default", "always", "never"])),
+    ("trust_madvise", Kind::Bool),
+    ("utrace", Kind::Bool),
+    ("xmalloc", Kind::Bool),
+    ("zero", Kind::Bool),
+];
+
+/// jemalloc 5.3's defaults, for the options whose default doesn't depend on
+/// the host.
+const DEFAULTS: &[(&str, &str)] = &[
+    ("abort", "false"),
+    ("abort_conf", "false"),
+    ("background_thread", "false"),
+    ("cache_oblivious", "true"),
+    ("dirty_decay_ms", "10000"),
+    ("dss", "secondary"),
+    ("hpa", "false"),
+    ("junk", "false"),
+    ("lg_extent_max_active_fit", "6"),
+    ("lg_tcache_max", "15"),
+    ("metadata_thp", "disabled"),
+    ("muzzy_decay_ms", "0"),
+    ("mutex_max_spin", "600"),
+    ("oversize_threshold", "8388608"),
+    ("percpu_arena", "disabled"),
+    ("prof", "false"),
+    ("retain", "true"),
+    ("stats_interval", "-1"),
+    ("stats_print", "false"),
+    ("tcache", "true"),
+    ("tcache_max", "32768"),
+    ("thp", "default"),
+    ("utrace", "false"),
+    ("xmalloc", "false"),
+    ("zero", "false"),
+];
+
+/// The value jemalloc uses for an option that isn't set, when it doesn't
+/// depend on the host.
+pub fn default_value(name: &str) -> Option<Value> {
+    let (_, default) = DEFAULTS.iter().find(|(option, _)| *option == name)?;
+    let (_, kind) = KNOWN_OPTIONS.iter().find(|(option, _)| *option == name)?;
+    parse_value(name, default, kind).ok()
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ParseError {
+    /// An entry that isn't `name:value`.
+    Malformed(String),
+    UnknownOption(String),
+    Duplicate(String),
+    InvalidValue {
+        name: String,
+        value: String,
+        expected: String,
+    },
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::Malformed(entry) => write!(f, "expected name:value, got {entry:?}"),
+            ParseError::UnknownOption(name) => write!(f, "unknown option {name}"),
+            ParseError::Duplicate(name) => write!(f, "{name} is set more than once"),
+            ParseError::InvalidValue {
+                name,
+                value,
+                expected,
+            } => write!(f, "invalid value {value:?} for {name}, expected {expected}"),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+fn parse_value(name: &str, value: &str, kind: &Kind) -> Result<Value, ParseError> {
+    let invalid = |expected: String| ParseError::InvalidValue {
+        name: name.to_string(),
+        value: value.to_string(),
+        expected,
+    };
+
+    match kind {
+        Kind::Bool => value
+            .parse()
+            .map(Value::Bool)
+            .map_err(|_| invalid("true or false".to_string())),
+        Kind::Int { min, max } => {
+            let expected = if *max == UNBOUNDED {
+                format!("an integer >= {min}")
+            } else {
+                format!("an integer in {min}..={max}")
+            };
+            match value.parse::<i64>() {
+                Ok(parsed) if (*min..=*max).contains(&parsed) => Ok(Value::Int(parsed)),
+                _ => Err(invalid(expected)),
+            }
+        }
+        Kind::Choice(choices) if choices.contains(&value) => Ok(Value::Str(value.to_string())),
+        Kind::Choice(choices) => Err(invalid(format!("one of {}", choices.join(", ")))),
+        Kind::Text => Ok(Value::Str(value.to_string())),
+    }
+}
+
+impl FromStr for MallocConf {
+    type Err = ParseError;
+
+    /// Parse a `MALLOC_CONF` string such as `narenas:2,tcache:false`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut conf = MallocConf::default();
+
+        for entry in s
+            .split(',')
+            .map(str::trim)
+            .filter(|entry| !entry.is_empty())
+        {
+            let Some((name, value)) = entry.split_once(':') else {
+                return Err(ParseError::Malformed(entry.to_string()));
+            };
+            let (name, value) = (name.trim(), value.trim());
+
+            let Some((_, kind)) = KNOWN_OPTIONS.iter().find(|(known, _)| *known == name) else {
+                return Err(ParseError::UnknownOption(name.to_string()));
+            };
+            if conf.get(name).is_some() {
+                return Err(ParseError::Duplicate(name.to_string()));
+            }
+
+            conf.options
+                .push((name.to_string(), parse_value(name, value, kind)?));
+        }
+
+        Ok(conf)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_options() {
+        let conf = " narenas:2, tcache:false,thp:never,"
+            .parse::<MallocConf>()
+            .unwrap();
+        assert_eq!(
+            conf.options,
+            vec![
+                ("narenas".to_string(), Value::Int(2)),
+                ("tcache".to_string(), Value::Bool(false)),
+                ("thp".to_string(), Value::Str("never".to_string())),
+            ]
+        );
+        assert_eq!(conf.to_string(), "narenas:2,tcache:false,thp:never");
+    }
+
+    #[test]
+    fn rejects_unknown_options() {
+        assert_eq!(
+            "narenas:2,bogus:1".parse::<MallocConf>(),
+            Err(ParseError::UnknownOption("bogus".to_string()))
+        );
+    }
+
+    #[test]
+    fn rejects_out_of_range_values() {
+        assert!(matches!(
+            "narenas:0".parse::<MallocConf>(),
+            Err(ParseError::InvalidValue { name, .. }) if name == "narenas"
+        ));
+        assert!(matches!(
+            "dirty_decay_ms:-2".parse::<MallocConf>(),
+            Err(ParseError::InvalidValue { .. })
+        ));
+        assert!(matches!(
+            "thp:sometimes".parse::<MallocConf>(),
+            Err(ParseError::InvalidValue { .. })
+        ));
+        assert!(matches!(
+            "tcache:yes".parse::<MallocConf>(),
+            Err(ParseError::InvalidValue { .. })
+        ));
+    }
+
+    #[test]
+    fn rejects_duplicates() {
+        assert_eq!(
+            "tcache:true,narenas:1,tcache:false".parse::<MallocConf>(),
+            Err(ParseError::Duplicate("tcache".to_string()))
+        );
+    }
+
+    #[test]
+    fn rejects_malformed_entries() {
+        assert_eq!(
+            "narenas:1,tcache".parse::<MallocConf>(),
+            Err(ParseError::Malformed("tcache".to_string()))
+        );
+    }
+
+    #[test]
+    fn accepts_options_outside_the_schema() {
+        let conf = "narenas:4,prof:true,lg_tcache_nslots_mul:2"
+            .parse::<MallocConf>()
+            .unwrap();
+        assert_eq!(conf.get("prof"), Some(&Value::Bool(true)));
+    }
+
+    #[test]
+    fn defaults_are_valid() {
+        for (name, _) in DEFAULTS {
+            assert!(default_value(name).is_some(), "bad default for {name}");
+        }
+    }
+}
@@ -0,0 +1,527 @@
+use bollard::{
+    container::{
+        Config, CreateContainerOptions, DownloadFromContainerOptions, LogOutput, LogsOptions,
+        RemoveContainerOptions, StartContainerOptions,
+    },
+    errors::Error,
+    exec::{CreateExecOptions, StartExecResults},
+    image::CreateImageOptions,
+    service::{CreateImageInfo, HostConfig, PortBinding},
+    Docker,
+};
+use futures::StreamExt;
+use rand::{distributions::Alphanumeric, Rng};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use tokio::{fs::OpenOptions, io::AsyncWriteExt, task::JoinHandle, time::timeout};
+
+use crate::{
+    cleanup::{self, Guard, Resource},
+    conf::RunConf,
+    error::RunError,
+    experiment::{Container, ProcessSpec},
+    memory::{Snapshot, SNAPSHOT_SCRIPT},
+    target::{self, Target},
+};
+
+/// Generate a random container name.
+fn get_name() -> String {
+    let mut rng = rand::thread_rng();
+    format!(
+        "groovin-{}",
+        (0..10)
+            .map(|_| rng.sample(Alphanumeric) as char)
+            .collect::<String>()
+    )
+}
+
+/// The status of a docker API error, or `None` if docker couldn't be reached.
+fn status(err: &Error) -> Option<u16> {
+    match err {
+        Error::DockerResponseServerError { status_code, .. } => Some(*status_code),
+        _ => None,
+    }
+}
+
+/// The host port docker published each of the container's ports on.
+async fn published_ports(
+    docker: &Docker,
+    name: &str,
+    ports: &[String],
+) -> Result<HashMap<String, u16>, RunError> {
+    if ports.is_empty() {
+        return Ok(HashMap::new());
+    }
+    let published = docker
+        .inspect_container(name, None)
+        .await
+        .map_err(|err| RunError::ContainerStart(err.to_string()))?
+        .network_settings
+        .and_then(|settings| settings.ports)
+        .unwrap_or_default();
+    ports
+        .iter()
+        .map(|port| {
+            published
+                .get(port)
+                .and_then(Option::as_ref)
+                .and_then(|bindings| {
+                    bindings
+                        .iter()
+                        .find_map(|binding| binding.host_port.as_ref()?.parse().ok())
+                })
+                .map(|host_port| (port.clone(), host_port))
+                .ok_or_else(|| RunError::ContainerStart(format!("{port} wasn't published")))
+        })
+        .collect()
+}
+
+/// Pull the image, showing its progress, and pin the containers to its
+/// digest. Fails if the libraries to preload aren't in the image. An image
+/// that can't be pulled is used as is if it's already here.
+pub async fn prepare(container: &mut Container) -> Result<(), RunError> {
+    let docker = Docker::connect_with_socket_defaults()
+        .map_err(|err| RunError::DockerUnavailable(err.to_string()))?;
+
+    if container.digest.is_none() {
+        let reference = container.reference();
+        println!("Pulling {reference}");
+        let mut pull = docker.create_image(
+            Some(CreateImageOptions {
+                from_image: container.image.as_str(),
+                tag: container.tag.as_str(),
+                ..Default::default()
+            }),
+            None,
+            None,
+        );
+        // Each layer's progress is only shown when its status changes.
+        let mut layers = HashMap::new();
+        while let Some(info) = pull.next().await {
+            match info {
+                Ok(CreateImageInfo {
+                    id,
+                    status: Some(status),
+                    ..
+                }) => {
+                    let id = id.unwrap_or_default();
+                    if layers.get(&id) != Some(&status) {
+                        println!("{id} {status}");
+                        layers.insert(id, status);
+                    }
+                }
+                Ok(_) => {}
+                Err(err) => {
+                    println!("Failed to pull {reference}, using the local image: {err}");
+                    break;
+                }
+            }
+        }
+    }
+
+    let image = container.pinned();
+    let inspect = docker
+        .inspect_image(&image)
+        .await
+        .map_err(|err| match status(&err) {
+            None => RunError::DockerUnavailable(err.to_string()),
+            Some(_) => RunError::ImagePull(format!("{image}: {err}")),
+        })?;
+    let digest = inspect
+        .id
+        .ok_or_else(|| RunError::ImagePull(format!("{image} has no ID")))?;
+    println!(
+        "Using {} {digest}{}",
+        container.reference(),
+        inspect
+            .repo_digests
+            .unwrap_or_default()
+            .first()
+            .map(|repo| format!(" ({repo})"))
+            .unwrap_or_default()
+    );
+    container.digest = Some(digest);
+
+    check_preload(&docker, container).await
+}
+
+/// Check every library to preload is in the image, by reading them out of
+/// a container that is never started.
+async fn check_preload(docker: &Docker, container: &Container) -> Result<(), RunError> {
+    if container.preload.is_empty() {
+        return Ok(());
+    }
+
+    let name = get_name();
+    let pid = std::process::id().to_string();
+    docker
+        .create_container(
+            Some(CreateContainerOptions {
+                name: &name,
+                platform: None,
+            }),
+            Config {
+                labels: Some(HashMap::from([(cleanup::LABEL, pid.as_str())])),
+                image: Some(container.pinned().as_str()),
+                ..Default::default()
+            },
+        )
+        .await
+        .map_err(|err| RunError::ContainerCreate(err.to_string()))?;
+    // Dropping the guard removes the container.
+    let _guard = Guard::new(Resource::Container(name.clone()));
+
+    for library in &container.preload {
+        let mut archive = docker
+            .download_from_container(&name, Some(DownloadFromContainerOptions { path: library }));
+        if let Some(Err(err)) = archive.next().await {
+            return Err(RunError::Setup(format!(
+                "{} has no {library} to preload: {err}",
+                container.reference()
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// The entrypoint used when processes have their own conf. Each wrapped
+/// binary is moved aside and replaced with a script that sets `MALLOC_CONF`
+/// before exec'ing it under its original name, then the image's own
+/// entrypoint is exec'd from the arguments. Binaries the image doesn't have
+/// are skipped.
+fn wrapping_entrypoint(wrapped: &[(&str, String)]) -> String {
+    let mut script = String::from("set -e\n");
+    for (binary, conf) in wrapped {
+        script.push_str(&format!(
+            "if [ -e {binary} ]; then\n\
+             mv {binary} {binary}.jemopt\n\
+             printf '#!/bin/bash\\nMALLOC_CONF=%s exec -a \"$0\" %s.jemopt \"$@\"\\n' '{conf}' {binary} > {binary}\n\
+             chmod +x {binary}\n\
+             fi\n"
+        ));
+    }
+    script.push_str("exec \"$@\"\n");
+    script
+}
+
+/// Append the container's output to the log until it stops.
+async fn follow_logs(docker: Docker, name: String, log: PathBuf) {
+    let Ok(mut file) = OpenOptions::new().append(true).open(&log).await else {
+        println!("Failed to open {}", log.display());
+        return;
+    };
+    let mut logs = docker.logs(
+        &name,
+        Some(LogsOptions::<String> {
+            follow: true,
+            stdout: true,
+            stderr: true,
+            ..Default::default()
+        }),
+    );
+    while let Some(Ok(output)) = logs.next().await {
+        if file.write_all(&output.into_bytes()).await.is_err() {
+            break;
+        }
+    }
+}
+
+/// A started container.
+pub struct Running {
+    docker: Docker,
+    name: String,
+    /// Copies the output into the log.
+    follow: JoinHandle<()>,
+    guard: Guard,
+}
+
+impl Target for Container {
+    type Running = Running;
+
+    async fn launch(
+        &self,
+        conf: &RunConf,
+        config: Option<&PathBuf>,
+        processes: &[ProcessSpec],
+        log: &Path,
+        ports: &[String],
+    ) -> Result<(Running, HashMap<String, u16>), RunError> {
+        let image = self.pinned();
+
+        let wrapped = conf
+            .overrides
+            .iter()
+            .map(|(process, conf)| {
+                processes
+                    .iter()
+                    .find(|spec| &spec.name == process)
+                    .and_then(|spec| spec.binary.as_deref())
+                    .map(|binary| (binary, target::with_stats(conf)))
+                    .ok_or_else(|| {
+                        RunError::Setup(format!("process {process} has no binary to give a conf"))
+                    })
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let docker = Docker::connect_with_socket_defaults()
+            .map_err(|err| RunError::DockerUnavailable(err.to_string()))?;
+        let name = get_name();
+
+        let env = self
+            .env
+            .iter()
+            .map(|(key, value)| (key.clone(), value.clone()))
+            .chain(target::malloc_env(conf, &self.preload))
+            .map(|(key, value)| format!("{key}={value}"))
+            .collect::<Vec<_>>();
+
+        // Wrapping replaces the entrypoint, so the entrypoint and command
+        // that would have run become the arguments to the wrapping script.
+        let (entrypoint, cmd) = if wrapped.is_empty() {
+            (self.entrypoint.clone(), self.cmd.clone())
+        } else {
+            let defaults = docker
+                .inspect_image(&image)
+                .await
+                .map_err(|err| match status(&err) {
+                    None => RunError::DockerUnavailable(err.to_string()),
+                    Some(_) => RunError::ImagePull(format!("{image}: {err}")),
+                })?
+                .config;
+            // Docker drops the image's command when the entrypoint is replaced.
+            let original = match &self.entrypoint {
+                Some(entrypoint) => entrypoint
+                    .iter()
+                    .cloned()
+                    .chain(self.cmd.clone().unwrap_or_default())
+                    .collect::<Vec<_>>(),
+                None => defaults
+                    .as_ref()
+                    .and_then(|config| config.entrypoint.clone())
+                    .unwrap_or_default()
+                    .into_iter()
+                    .chain(self.cmd.clone().unwrap_or_else(|| {
+                        defaults
+                            .as_ref()
+                            .and_then(|config| config.cmd.clone())
+                            .unwrap_or_default()
+                    }))
+                    .collect(),
+            };
+            (
+                Some(vec![
+                    "bash".to_string(),
+                    "-c".to_string(),
+                    wrapping_entrypoint(&wrapped),
+                    "jemopt".to_string(),
+                ]),
+                Some(original),
+            )
+        };
+
+        let mut volumes = self.binds.clone();
+
+        if let Some(conf) = config {
+            let path = self.config_path.as_deref().ok_or_else(|| {
+                RunError::Setup("the container needs a config_path to use a config".to_string())
+            })?;
+            volumes.push(format!("{conf}:{path}", conf = conf.display()));
+        }
+
+        let pid = std::process::id().to_string();
+
+        docker
+            .create_container(
+                Some(CreateContainerOptions {
+                    name: &name,
+                    platform: None,
+                }),
+                Config {
+                    hostname: self.hostname.as_deref(),
+                    labels: Some(HashMap::from([(cleanup::LABEL, pid.as_str())])),
+                    image: Some(&image),
+                    exposed_ports: Some(
+                        ports
+                            .iter()
+                            .map(|port| (port.as_str(), HashMap::new()))
+                            .collect(),
+                    ),
+                    host_config: Some(HostConfig {
+                        network_mode: self.network.clone(),
+                        binds: Some(volumes),
+                        port_bindings: Some(
+                            ports
+                                .iter()
+                                .map(|port| {
+                                    (
+                                        port.clone(),
+                                        Some(vec![PortBinding {
+                                            host_ip: Some("127.0.0.1".to_string()),
+                                            // Docker picks a free ephemeral port,
+                                            // which is freed again when the
+                                            // container goes.
+                                            host_port: None,
+                                        }]),
+                                    )
+                                })
+                                .collect(),
+                        ),
+                        nano_cpus: self.cpus.map(|cpus| (cpus * 1e9) as i64),
+                        cpuset_cpus: self.cpuset.clone(),
+                        memory: self.memory.map(|mib| (mib * 1024 * 1024) as i64),
+                        ..Default::default()
+                    }),
+                    env: Some(env.iter().map(String::as_str).collect()),
+                    entrypoint: entrypoint
+                        .as_ref()
+                        .map(|args| args.iter().map(String::as_str).collect()),
+                    cmd: cmd
+                        .as_ref()
+                        .map(|args| args.iter().map(String::as_str).collect()),
+                    network_disabled: Some(false),
+                    ..Default::default()
+                },
+            )
+            .await
+            .map_err(|err| match status(&err) {
+                None => RunError::DockerUnavailable(err.to_string()),
+                Some(404) => RunError::ImagePull(format!("{image}: {err}")),
+                Some(_) => RunError::ContainerCreate(err.to_string()),
+            })?;
+
+        // The container is kept once it exits so its state and logs can be
+        // read, and is removed on stopping, or by the guard if anything fails.
+        let guard = Guard::new(Resource::Container(name.clone()));
+
+        docker
+            .start_container(&name, None::<StartContainerOptions<String>>)
+            .await
+            .map_err(|err| RunError::ContainerStart(err.to_string()))?;
+
+        let follow = tokio::spawn(follow_logs(docker.clone(), name.clone(), log.to_path_buf()));
+
+        let published = published_ports(&docker, &name, ports).await?;
+
+        println!(
+            "Container {name} ports {published:?} running with {:?}",
+            conf.to_string()
+        );
+
+        Ok((
+            Running {
+                docker,
+                name,
+                follow,
+                guard,
+            },
+            published,
+        ))
+    }
+
+    async fn snapshot(
+        &self,
+        running: &Running,
+        elapsed: f64,
+        processes: &[ProcessSpec],
+    ) -> Result<Snapshot, RunError> {
+        let Running { docker, name, .. } = running;
+        let failed = |err: Error| match status(&err) {
+            Some(404 | 409) => RunError::ContainerExited(err.to_string()),
+            _ => RunError::MeasurementFailed(err.to_string()),
+        };
+        let exec = docker
+            .create_exec(
+                name,
+                CreateExecOptions {
+                    attach_stdout: Some(true),
+                    cmd: Some(vec!["sh", "-c", SNAPSHOT_SCRIPT]),
+                    ..Default::default()
+                },
+            )
+            .await
+            .map_err(failed)?;
+        let exec = docker.start_exec(&exec.id, None).await.map_err(failed)?;
+
+        let StartExecResults::Attached { mut output, .. } = exec else {
+            return Err(RunError::MeasurementFailed("detached exec".to_string()));
+        };
+
+        let mut stdout = Vec::new();
+        while let Some(Ok(o)) = output.next().await {
+            let (LogOutput::StdErr { message }
+            | LogOutput::StdOut { message }
+            | LogOutput::StdIn { message }
+            | LogOutput::Console { message }) = o;
+            stdout.extend_from_slice(&message);
+        }
+
+        Snapshot::parse(&String::from_utf8_lossy(&stdout), elapsed, processes)
+    }
+
+    async fn crashed(&self, running: &mut Running) -> Option<String> {
+        let state = match running.docker.inspect_container(&running.name, None).await {
+            Ok(container) => container.state?,
+            Err(err) if status(&err) == Some(404) => return Some("container removed".to_string()),
+            // Can't tell, so the snapshots will have to.
+            Err(_) => return None,
+        };
+        if state.oom_killed == Some(true) {
+            Some("OOM killed".to_string())
+        } else if state.running == Some(false) {
+            Some(format!(
+                "exited with {}",
+                state.exit_code.unwrap_or_default()
+            ))
+        } else {
+            None
+        }
+    }
+
+    async fn logs(&self, running: &Running) -> String {
+        let mut logs = running.docker.logs(
+            &running.name,
+            Some(LogsOptions::<String> {
+                stdout: true,
+                stderr: true,
+                tail: target::LOG_TAIL.to_string(),
+                ..Default::default()
+            }),
+        );
+        let mut tail = String::new();
+        while let Some(Ok(output)) = logs.next().await {
+            tail.push_str(&output.to_string());
+        }
+        tail
+    }
+
+    async fn stop(&self, running: Running) {
+        match running.docker.stop_container(&running.name, None).await {
+            // Already stopped.
+            Err(err) if matches!(status(&err), Some(304 | 404)) => {}
+            Err(err) => println!("Failed to stop {}: {err}", running.name),
+            Ok(()) => {}
+        }
+        // The output ends once it has stopped, with jemalloc's stats.
+        let _ = timeout(Duration::from_secs(10), running.follow).await;
+        match running
+            .docker
+            .remove_container(
+                &running.name,
+                Some(RemoveContainerOptions {
+                    force: true,
+                    ..Default::default()
+                }),
+            )
+            .await
+        {
+            Err(err) if status(&err) != Some(404) => {
+                // Dropping the guard tries again.
+                println!("Failed to remove {}: {err}", running.name)
+            }
+            _ => running.guard.release(),
+        }
+    }
+}
@@ -1,31 +1,492 @@
-use tokio::task::yield_now;
+use rand::{
+    distributions::{Distribution, WeightedIndex},
+    rngs::StdRng,
+    seq::SliceRandom,
+    Rng, SeedableRng,
+};
+use serde::Deserialize;
+use std::{
+    collections::BTreeMap,
+    fmt::Write as _,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use tokio::{
+    io::AsyncWriteExt,
+    net::{UdpSocket, UnixDatagram, UnixStream},
+    task::yield_now,
+    time::{sleep_until, timeout_at, Duration, Instant},
+};
 
-use dogstatsd::{Client, Options};
-use tokio::time::{Duration, Instant};
+use crate::{
+    cache::stable_hash,
+    capture::{self, Packet},
+    load::{Pacer, Shape, TICK},
+};
 
-pub async fn spam(port: u16, timelimit: Duration) {
-    let start = Instant::now();
-    
-    let to_addr = format!("127.0.0.1:{port}");
-    let client = Client::new(Options {
-        to_addr,
-        ..Options::default()
-    }).unwrap();
-    let tags = &["nong:wong"];
+/// How long a write to a socket may block before the packet is dropped.
+const WRITE_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// The largest payload a UDP packet can carry.
+const MAX_DATAGRAM: usize = 65507;
+
+/// The dogstatsd kinds of metric.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum MetricType {
+    Counter,
+    Gauge,
+    Histogram,
+    Distribution,
+    Set,
+    Timing,
+}
+
+impl MetricType {
+    fn name(self) -> &'static str {
+        match self {
+            MetricType::Counter => "counter",
+            MetricType::Gauge => "gauge",
+            MetricType::Histogram => "histogram",
+            MetricType::Distribution => "distribution",
+            MetricType::Set => "set",
+            MetricType::Timing => "timing",
+        }
+    }
+
+    /// The type as written in a packet.
+    fn code(self) -> &'static str {
+        match self {
+            MetricType::Counter => "c",
+            MetricType::Gauge => "g",
+            MetricType::Histogram => "h",
+            MetricType::Distribution => "d",
+            MetricType::Set => "s",
+            MetricType::Timing => "ms",
+        }
+    }
+}
+
+/// How the values sent are drawn. Counts, set members and timings are
+/// rounded.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum Values {
+    Constant { value: f64 },
+    Uniform { min: f64, max: f64 },
+    Exponential { mean: f64 },
+}
+
+impl Values {
+    fn sample(&self, rng: &mut StdRng) -> f64 {
+        match *self {
+            Values::Constant { value } => value,
+            Values::Uniform { min, max } if min < max => rng.gen_range(min..max),
+            Values::Uniform { min, .. } => min,
+            Values::Exponential { mean } => -mean * (1.0 - rng.gen::<f64>()).ln(),
+        }
+    }
+}
+
+/// A capture replayed in place of generated metrics, over and over until
+/// the run ends. Packets with offsets are sent at their time, scaled by
+/// `speed`, and a plain packet log is sent at the load's shape.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Replay {
+    pub path: PathBuf,
+    /// 2.0 replays the capture twice as fast.
+    #[serde(default = "default_speed")]
+    pub speed: f64,
+    #[serde(skip)]
+    packets: Arc<Vec<Packet>>,
+    /// Hash of the capture, so runs of a different capture aren't cached
+    /// together.
+    #[serde(skip)]
+    pub hash: u64,
+}
+
+fn default_speed() -> f64 {
+    1.0
+}
+
+/// A Unix socket the load is sent to instead of UDP, at its path on this
+/// machine.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum Socket {
+    Datagram {
+        path: PathBuf,
+    },
+    /// Each packet is sent after its length, as four little endian bytes.
+    Stream {
+        path: PathBuf,
+    },
+}
+
+impl Socket {
+    pub fn path(&self) -> &Path {
+        match self {
+            Socket::Datagram { path } | Socket::Stream { path } => path,
+        }
+    }
+}
+
+/// The shape of the dogstatsd load. The metric names are split between the
+/// types of the mix, and every metric sent picks a name and a value for each tag at
+/// random, so the number of contexts is up to
+/// `metrics * tag_values ^ tags`.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(default)]
+pub struct LoadProfile {
+    /// Metric names are `<prefix>.<type><n>`.
+    pub prefix: String,
+    /// How many distinct metric names there are.
+    pub metrics: usize,
+    /// Tags on every metric.
+    pub tags: usize,
+    /// Distinct values of each tag.
+    pub tag_values: usize,
+    /// The relative weight of each metric type.
+    pub types: BTreeMap<MetricType, f64>,
+    pub values: Values,
+    /// Metrics a second over the run. Without one it sends as fast as it
+    /// can, which depends on the host.
+    pub shape: Option<Shape>,
+    /// Packs metrics into packets of up to this many bytes, separated by
+    /// newlines. Without it each metric is its own packet.
+    pub packet_size: Option<usize>,
+    /// Sends over a Unix socket instead of the target's dogstatsd port.
+    pub socket: Option<Socket>,
+    /// Seeds the random choices, so every run gets the same load.
+    pub seed: u64,
+    /// Sends a capture instead of the generated metrics.
+    pub replay: Option<Replay>,
+}
+
+impl Default for LoadProfile {
+    fn default() -> Self {
+        // The 20,000 contexts of jemopt's original load, 10,000 counters and
+        // 10,000 gauges all with the same tag.
+        LoadProfile {
+            prefix: "ziggle".to_string(),
+            metrics: 20000,
+            tags: 1,
+            tag_values: 1,
+            types: BTreeMap::from([(MetricType::Counter, 1.0), (MetricType::Gauge, 1.0)]),
+            values: Values::Uniform {
+                min: 0.0,
+                max: 1000.0,
+            },
+            shape: None,
+            packet_size: None,
+            socket: None,
+            seed: 0,
+            replay: None,
+        }
+    }
+}
+
+impl LoadProfile {
+    /// Check the profile and read the capture to replay.
+    pub fn prepare(&mut self) -> Result<(), String> {
+        if let Some(replay) = &mut self.replay {
+            if replay.speed <= 0.0 {
+                return Err("the replay speed must be positive".to_string());
+            }
+            let contents = fs::read(&replay.path)
+                .map_err(|err| format!("can't read {}: {err}", replay.path.display()))?;
+            replay.hash = stable_hash(&contents);
+            let mut packets = capture::read(&replay.path)
+                .map_err(|err| format!("can't read {}: {err}", replay.path.display()))?;
+            if packets.is_empty() {
+                return Err(format!("{} has no packets", replay.path.display()));
+            }
+            if packets
+                .iter()
+                .any(|packet| packet.offset.is_some_and(|offset| !offset.is_finite()))
+            {
+                return Err(format!(
+                    "{} has an offset out of range",
+                    replay.path.display()
+                ));
+            }
+            capture::order(&mut packets);
+            replay.packets = Arc::new(packets);
+        }
+        if self.metrics == 0 {
+            return Err("there must be at least one metric".to_string());
+        }
+        if self.tags > 0 && self.tag_values == 0 {
+            return Err("tags need at least one value".to_string());
+        }
+        if let Some(shape) = &self.shape {
+            shape.check()?;
+        }
+        match (self.packet_size, &self.socket) {
+            (Some(0), _) => return Err("the packet size must be positive".to_string()),
+            (Some(size), None | Some(Socket::Datagram { .. })) if size > MAX_DATAGRAM => {
+                return Err(format!(
+                    "a packet size of {size} doesn't fit in a datagram, the most is {MAX_DATAGRAM}"
+                ));
+            }
+            _ => {}
+        }
+        WeightedIndex::new(self.types.values())
+            .map(|_| ())
+            .map_err(|err| format!("bad metric type weights: {err}"))
+    }
+
+    /// The type of each metric name, split between the types by their
+    /// weights and shuffled. The odd names left over are drawn by weight.
+    fn types(&self, rng: &mut StdRng) -> Vec<MetricType> {
+        let total = self.types.values().sum::<f64>();
+        let mut types = self
+            .types
+            .iter()
+            .flat_map(|(kind, weight)| {
+                let count = (self.metrics as f64 * weight / total).floor() as usize;
+                std::iter::repeat(*kind).take(count)
+            })
+            .collect::<Vec<_>>();
+        let kinds = self.types.keys().copied().collect::<Vec<_>>();
+        let weights =
+            WeightedIndex::new(self.types.values()).expect("load profile should be checked");
+        while types.len() < self.metrics {
+            types.push(kinds[weights.sample(rng)]);
+        }
+        types.shuffle(rng);
+        types
+    }
+}
 
-    loop {
-	if start.elapsed() > timelimit {
-	    return;
-	}
+/// Write one metric of the profile into the line.
+fn metric(line: &mut String, profile: &LoadProfile, types: &[MetricType], rng: &mut StdRng) {
+    let n = rng.gen_range(0..types.len());
+    let kind = types[n];
+    let value = profile.values.sample(rng);
+    line.clear();
+    let _ = match kind {
+        MetricType::Counter | MetricType::Set | MetricType::Timing => write!(
+            line,
+            "{}.{}{n}:{}|{}",
+            profile.prefix,
+            kind.name(),
+            value.round() as i64,
+            kind.code()
+        ),
+        _ => write!(
+            line,
+            "{}.{}{n}:{value}|{}",
+            profile.prefix,
+            kind.name(),
+            kind.code()
+        ),
+    };
+    for tag in 0..profile.tags {
+        let separator = if tag == 0 { "|#" } else { "," };
+        let _ = write!(
+            line,
+            "{separator}tag{tag}:value{}",
+            rng.gen_range(0..profile.tag_values)
+        );
+    }
+}
 
-        for i in 0..10000 {
-            client.incr(&format!("ziggle.counter{i}"), tags).unwrap();
-            client.decr(&format!("ziggle.counter{i}"), tags).unwrap();
-            client
-                .gauge(&format!("ziggle.guage{i}"), "12345", tags)
-                .unwrap();
+/// Sends the load, packing metrics into packets of up to the packet size
+/// like a client's buffer. Packets that can't be sent are dropped, as a
+/// client would.
+struct Sender {
+    socket: Connection,
+    packet_size: usize,
+    buffer: Vec<u8>,
+    packets: u64,
+    dropped: u64,
+    /// When the load ends. Writes give up by then, so a slow reader can't
+    /// hold the run's last snapshot back.
+    deadline: Instant,
+}
+
+enum Connection {
+    Udp(UdpSocket),
+    Datagram(UnixDatagram, PathBuf),
+    /// Connected when the first packet is sent, and again after a failed
+    /// write, since the target makes the socket once it has started.
+    Stream(Option<UnixStream>, PathBuf),
+}
+
+impl Sender {
+    async fn new(port: Option<u16>, profile: &LoadProfile, deadline: Instant) -> io::Result<Self> {
+        let socket = match &profile.socket {
+            None => {
+                let port = port.ok_or_else(|| {
+                    io::Error::new(io::ErrorKind::InvalidInput, "udp load needs a port")
+                })?;
+                let socket = UdpSocket::bind("127.0.0.1:0").await?;
+                socket.connect(("127.0.0.1", port)).await?;
+                Connection::Udp(socket)
+            }
+            Some(Socket::Datagram { path }) => {
+                Connection::Datagram(UnixDatagram::unbound()?, path.clone())
+            }
+            Some(Socket::Stream { path }) => Connection::Stream(None, path.clone()),
+        };
+        Ok(Sender {
+            socket,
+            packet_size: profile.packet_size.unwrap_or(0),
+            buffer: Vec::new(),
+            packets: 0,
+            dropped: 0,
+            deadline,
+        })
+    }
+
+    /// Add a payload to the packet, sending the packet first if the payload
+    /// wouldn't fit.
+    async fn send(&mut self, payload: &[u8]) {
+        if !self.buffer.is_empty() && self.buffer.len() + 1 + payload.len() > self.packet_size {
+            self.flush().await;
+        }
+        if !self.buffer.is_empty() {
+            self.buffer.push(b'\n');
         }
+        self.buffer.extend_from_slice(payload);
+        if self.buffer.len() >= self.packet_size {
+            self.flush().await;
+        }
+    }
 
-	yield_now().await;
+    /// Send the packet so far.
+    async fn flush(&mut self) {
+        if self.buffer.is_empty() {
+            return;
+        }
+        let until = (Instant::now() + WRITE_TIMEOUT).min(self.deadline);
+        let sent = match &mut self.socket {
+            Connection::Udp(socket) => timeout_at(until, socket.send(&self.buffer))
+                .await
+                .is_ok_and(|sent| sent.is_ok()),
+            Connection::Datagram(socket, path) => {
+                timeout_at(until, socket.send_to(&self.buffer, &*path))
+                    .await
+                    .is_ok_and(|sent| sent.is_ok())
+            }
+            Connection::Stream(stream, path) => {
+                if stream.is_none() {
+                    *stream = timeout_at(until, UnixStream::connect(&*path))
+                        .await
+                        .ok()
+                        .and_then(Result::ok);
+                }
+                // Each packet is framed by its length, as the agent expects.
+                let mut frame = (self.buffer.len() as u32).to_le_bytes().to_vec();
+                frame.extend_from_slice(&self.buffer);
+                let written = match stream.as_mut() {
+                    Some(stream) => timeout_at(until, stream.write_all(&frame))
+                        .await
+                        .is_ok_and(|written| written.is_ok()),
+                    None => false,
+                };
+                // A partly written frame would garble the rest.
+                if !written {
+                    *stream = None;
+                }
+                written
+            }
+        };
+        match sent {
+            true => self.packets += 1,
+            false => self.dropped += 1,
+        }
+        self.buffer.clear();
     }
 }
+
+/// Send the captured packets at their offsets, scaled by the speed, going
+/// round again when the capture ends. The packets are in time order, each
+/// with an offset. Returns how many were sent.
+async fn replay_timed(sender: &mut Sender, replay: &Replay, timelimit: Duration) -> u64 {
+    let start = Instant::now();
+    let packets = replay
+        .packets
+        .iter()
+        .map(|packet| (packet.offset.unwrap_or(0.0), packet.payload.as_bytes()))
+        .collect::<Vec<_>>();
+    let first = packets[0].0;
+    let length = (packets[packets.len() - 1].0 - first).max(TICK.as_secs_f64());
+
+    let mut sent = 0;
+    for round in 0.. {
+        for (offset, payload) in &packets {
+            let at =
+                Duration::from_secs_f64((round as f64 * length + offset - first) / replay.speed);
+            if at > timelimit || start.elapsed() > timelimit {
+                return sent;
+            }
+            if Instant::now() < start + at {
+                sender.flush().await;
+                sleep_until(start + at).await;
+            } else {
+                // Behind, so let the rest of the run have the thread.
+                yield_now().await;
+            }
+            sender.send(payload).await;
+            sent += 1;
+        }
+    }
+    sent
+}
+
+/// Send the load for the time limit, to the port on localhost unless it
+/// goes over a socket.
+pub async fn spam(port: Option<u16>, timelimit: Duration, profile: LoadProfile) {
+    let start = Instant::now();
+
+    let mut sender = match Sender::new(port, &profile, start + timelimit).await {
+        Ok(sender) => sender,
+        Err(err) => {
+            println!("Failed to set up the dogstatsd load: {err}");
+            return;
+        }
+    };
+    let mut pacer = Pacer::new(timelimit, profile.shape.clone());
+    let (sent, what) = match &profile.replay {
+        Some(replay) => {
+            let sent = if replay.packets.iter().any(|packet| packet.offset.is_some()) {
+                replay_timed(&mut sender, replay, timelimit).await
+            } else {
+                let mut packets = replay.packets.iter().cycle();
+                while let Some(count) = pacer.next().await {
+                    for packet in packets.by_ref().take(count as usize) {
+                        sender.send(packet.payload.as_bytes()).await;
+                    }
+                    sender.flush().await;
+                }
+                pacer.sent().0
+            };
+            (sent, "captured packets")
+        }
+        None => {
+            let mut rng = StdRng::seed_from_u64(profile.seed);
+            let types = profile.types(&mut rng);
+            let mut line = String::new();
+            while let Some(count) = pacer.next().await {
+                for _ in 0..count {
+                    metric(&mut line, &profile, &types, &mut rng);
+                    sender.send(line.as_bytes()).await;
+                }
+                // Like a client's flush interval, so a slow load isn't held
+                // back waiting for a full packet.
+                sender.flush().await;
+            }
+            (pacer.sent().0, "metrics")
+        }
+    };
+    sender.flush().await;
+    println!(
+        "Sent {sent} {what} in {} packets, {:.0} a second, {} packets dropped",
+        sender.packets,
+        sent as f64 / start.elapsed().as_secs_f64(),
+        sender.dropped
+    );
+}
@@ -0,0 +1,133 @@
+use std::{
+    collections::BTreeMap,
+    error::Error,
+    fmt,
+    sync::{Arc, Mutex},
+};
+
+/// Why a run failed to produce a sample.
+#[derive(Debug)]
+pub enum RunError {
+    DockerUnavailable(String),
+    /// The image is missing or couldn't be fetched.
+    ImagePull(String),
+    /// Creating the container failed, such as on a name clash.
+    ContainerCreate(String),
+    ContainerStart(String),
+    /// The container, or local workload, stopped before the run ended.
+    ContainerExited(String),
+    /// The workload exited, was killed for running out of memory, or a
+    /// process restarted, during the run.
+    Crashed {
+        reason: String,
+        logs: String,
+    },
+    /// A required process wasn't running when measured.
+    ProcessMissing(String),
+    /// The memory couldn't be read.
+    MeasurementFailed(String),
+    /// The run went on well past its length.
+    Timeout,
+    /// The experiment can't be run as configured.
+    Setup(String),
+}
+
+/// What to do about a failed run.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Failure {
+    /// The conf is likely to blame, or running it again won't help, so the
+    /// chromosome gets the worst fitness.
+    Penalise,
+    /// The environment failed, so the run is worth trying again.
+    Retry,
+}
+
+impl RunError {
+    pub fn name(&self) -> &'static str {
+        match self {
+            RunError::DockerUnavailable(_) => "DockerUnavailable",
+            RunError::ImagePull(_) => "ImagePull",
+            RunError::ContainerCreate(_) => "ContainerCreate",
+            RunError::ContainerStart(_) => "ContainerStart",
+            RunError::ContainerExited(_) => "ContainerExited",
+            RunError::Crashed { .. } => "Crashed",
+            RunError::ProcessMissing(_) => "ProcessMissing",
+            RunError::MeasurementFailed(_) => "MeasurementFailed",
+            RunError::Timeout => "Timeout",
+            RunError::Setup(_) => "Setup",
+        }
+    }
+
+    pub fn failure(&self) -> Failure {
+        match self {
+            RunError::DockerUnavailable(_)
+            | RunError::ImagePull(_)
+            | RunError::ContainerCreate(_)
+            | RunError::ContainerStart(_)
+            | RunError::MeasurementFailed(_)
+            | RunError::Timeout => Failure::Retry,
+            RunError::ContainerExited(_)
+            | RunError::Crashed { .. }
+            | RunError::ProcessMissing(_)
+            | RunError::Setup(_) => Failure::Penalise,
+        }
+    }
+}
+
+impl fmt::Display for RunError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RunError::DockerUnavailable(err) => write!(f, "docker is unavailable: {err}"),
+            RunError::ImagePull(err) => write!(f, "image unavailable: {err}"),
+            RunError::ContainerCreate(err) => write!(f, "failed to create the container: {err}"),
+            RunError::ContainerStart(err) => write!(f, "failed to start: {err}"),
+            RunError::ContainerExited(err) => write!(f, "exited during the run: {err}"),
+            RunError::Crashed { reason, logs } => write!(f, "crashed, {reason}:\n{logs}"),
+            RunError::ProcessMissing(process) => write!(f, "process {process} not found"),
+            RunError::MeasurementFailed(err) => write!(f, "failed to measure memory: {err}"),
+            RunError::Timeout => write!(f, "timed out"),
+            RunError::Setup(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+impl Error for RunError {}
+
+/// Failures shared between the fitness threads.
+pub type SharedFailures = Arc<Mutex<Failures>>;
+
+/// Every failed run, summarised at the end.
+#[derive(Debug, Default)]
+pub struct Failures {
+    /// How often each error happened, and how many of those were retried.
+    counts: BTreeMap<&'static str, (usize, usize)>,
+    /// Confs that got no samples at all.
+    penalised: usize,
+}
+
+impl Failures {
+    pub fn record(&mut self, err: &RunError, retried: bool) {
+        let (count, retries) = self.counts.entry(err.name()).or_default();
+        *count += 1;
+        if retried {
+            *retries += 1;
+        }
+    }
+
+    pub fn penalise(&mut self) {
+        self.penalised += 1;
+    }
+}
+
+impl fmt::Display for Failures {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.counts.is_empty() {
+            return write!(f, "no failed runs");
+        }
+
+        for (name, (count, retries)) in &self.counts {
+            write!(f, "{name} x{count} ({retries} retried), ")?;
+        }
+        write!(f, "{} confs penalised", self.penalised)
+    }
+}
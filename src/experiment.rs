@@ -0,0 +1,382 @@
+use clap::Args;
+use regex::Regex;
+use serde::Deserialize;
+use std::{collections::BTreeMap, fs, path::PathBuf};
+
+use crate::{cache::stable_hash, dogstatsd::LoadProfile, load::Generator};
+
+/// The experiment used when no experiment file is given.
+const DEFAULT_EXPERIMENT: &str = include_str!("../experiment.toml");
+
+/// What is run for each conf, and how it is measured.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Experiment {
+    /// Experiment files without a target run the built in agent.
+    #[serde(default = "default_target")]
+    pub target: TargetSpec,
+
+    #[serde(rename = "process")]
+    pub processes: Vec<ProcessSpec>,
+
+    /// The dogstatsd load sent with `--payloads`.
+    #[serde(default)]
+    pub load: LoadProfile,
+
+    /// Other load sent alongside it.
+    #[serde(default, rename = "generator")]
+    pub generators: Vec<Generator>,
+
+    /// Hash of the experiment file, so cached samples from a different
+    /// experiment are never reused.
+    #[serde(skip)]
+    pub hash: u64,
+}
+
+/// What each conf is run in, picked by the `type` key.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum TargetSpec {
+    Docker(Container),
+    Process(LocalProcess),
+    Command(CommandTarget),
+}
+
+impl TargetSpec {
+    /// What is run, for telling cached samples apart.
+    pub fn name(&self) -> String {
+        match self {
+            TargetSpec::Docker(container) => match &container.digest {
+                Some(digest) => format!("{}@{digest}", container.reference()),
+                None => container.reference(),
+            },
+            TargetSpec::Process(process) => process.binary.clone(),
+            TargetSpec::Command(command) => command.launch.clone(),
+        }
+    }
+
+    /// The port dogstatsd load is sent to, if it takes any.
+    pub fn dogstatsd_port(&self) -> Option<u16> {
+        match self {
+            TargetSpec::Docker(container) => container.dogstatsd_port,
+            TargetSpec::Process(process) => process.dogstatsd_port,
+            TargetSpec::Command(command) => command.dogstatsd_port,
+        }
+    }
+
+    /// Whether its snapshots have cgroup figures. A command's own snapshot
+    /// may print them.
+    pub fn has_cgroup(&self) -> bool {
+        match self {
+            TargetSpec::Docker(_) => true,
+            TargetSpec::Process(process) => process.cgroup.is_some(),
+            TargetSpec::Command(command) => command.snapshot.is_some(),
+        }
+    }
+
+    /// The CPUs and MiB of memory each run is limited to.
+    pub fn resources(&self) -> (Option<f64>, Option<u64>) {
+        match self {
+            TargetSpec::Docker(container) => (container.cpus, container.memory),
+            TargetSpec::Process(process) => (process.cpus, process.memory),
+            TargetSpec::Command(_) => (None, None),
+        }
+    }
+
+    /// Pin the runs to the cores, as a cpuset list like `0,1`.
+    pub fn pin(&mut self, cpuset: &str) {
+        let field = match self {
+            TargetSpec::Docker(container) => &mut container.cpuset,
+            TargetSpec::Process(process) => &mut process.cpuset,
+            TargetSpec::Command(command) => &mut command.cpuset,
+        };
+        *field = Some(cpuset.to_string());
+    }
+}
+
+/// A docker container.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Container {
+    pub image: String,
+    #[serde(default = "default_tag")]
+    pub tag: String,
+    /// The image ID, `sha256:...`, which pins the exact build. Resolved
+    /// from the tag before running when not given, so every run uses the
+    /// same build.
+    pub digest: Option<String>,
+    pub hostname: Option<String>,
+    /// Docker's default network when not given.
+    pub network: Option<String>,
+    pub cpus: Option<f64>,
+    /// The cores to run on, such as `0-3`.
+    pub cpuset: Option<String>,
+    /// Limit in MiB.
+    pub memory: Option<u64>,
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+    /// Extra volumes, as `host:container[:options]`.
+    #[serde(default)]
+    pub binds: Vec<String>,
+    /// Replaces the image's entrypoint.
+    pub entrypoint: Option<Vec<String>>,
+    /// Replaces the image's command.
+    pub cmd: Option<Vec<String>>,
+    /// Libraries preloaded whenever there is a conf, the first of which
+    /// should be jemalloc or load it.
+    #[serde(default)]
+    pub preload: Vec<String>,
+    /// Where the `--config` file is mounted.
+    pub config_path: Option<String>,
+    /// The container's UDP port dogstatsd load is sent to.
+    pub dogstatsd_port: Option<u16>,
+}
+
+/// A binary spawned directly on this machine, with no docker needed. Its
+/// memory is read from /proc for it and all its descendants, or everything
+/// in its cgroup.
+#[derive(Debug, Clone, Deserialize)]
+pub struct LocalProcess {
+    pub binary: String,
+    /// `{config}` in an argument is replaced with the `--config` path.
+    #[serde(default)]
+    pub args: Vec<String>,
+    /// Added to jemopt's own environment.
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+    #[serde(default)]
+    pub preload: Vec<String>,
+    /// The UDP port on localhost dogstatsd load is sent to.
+    pub dogstatsd_port: Option<u16>,
+    /// A cgroup v2 directory, such as `/sys/fs/cgroup/jemopt`, under which
+    /// each run gets its own cgroup. It is created if missing. Without it
+    /// the process tree is measured and there are no cgroup figures.
+    pub cgroup: Option<PathBuf>,
+    /// CPU limit, which needs a cgroup.
+    pub cpus: Option<f64>,
+    /// The cores to run on, such as `0-3`.
+    pub cpuset: Option<String>,
+    /// Memory limit in MiB, which needs a cgroup.
+    pub memory: Option<u64>,
+}
+
+/// Shell commands run on this machine, for workloads jemopt can't start
+/// itself, such as a compose file or a remote host.
+#[derive(Debug, Clone, Deserialize)]
+pub struct CommandTarget {
+    /// Starts the workload and keeps running until it is stopped. `{config}`
+    /// is replaced with the `--config` path.
+    pub launch: String,
+    /// Prints the memory in the same format as the docker snapshot script.
+    /// Without it the launch command's own process tree is measured.
+    pub snapshot: Option<String>,
+    /// Run before the launch command's process group is terminated.
+    pub stop: Option<String>,
+    #[serde(default)]
+    pub env: BTreeMap<String, String>,
+    #[serde(default)]
+    pub preload: Vec<String>,
+    pub dogstatsd_port: Option<u16>,
+    /// The cores to run on, such as `0-3`.
+    pub cpuset: Option<String>,
+}
+
+impl Container {
+    /// The image reference, `image:tag`.
+    pub fn reference(&self) -> String {
+        format!("{}:{}", self.image, self.tag)
+    }
+
+    /// What the containers are created from, the digest once it's known.
+    pub fn pinned(&self) -> String {
+        self.digest.clone().unwrap_or_else(|| self.reference())
+    }
+}
+
+fn default_tag() -> String {
+    "latest".to_string()
+}
+
+fn default_target() -> TargetSpec {
+    #[derive(Deserialize)]
+    struct Default {
+        target: TargetSpec,
+    }
+
+    toml::from_str::<Default>(DEFAULT_EXPERIMENT)
+        .expect("built in experiment should be valid")
+        .target
+}
+
+/// Command line overrides for the experiment's docker target.
+#[derive(Args, Debug, Clone, Default)]
+pub struct ContainerArgs {
+    /// The image to run, replacing the experiment's
+    #[arg(long)]
+    pub image: Option<String>,
+
+    /// The image tag to run
+    #[arg(long)]
+    pub tag: Option<String>,
+
+    /// The container's hostname
+    #[arg(long)]
+    pub hostname: Option<String>,
+
+    /// The docker network to run in
+    #[arg(long)]
+    pub network: Option<String>,
+
+    /// CPU limit, may be fractional
+    #[arg(long)]
+    pub cpus: Option<f64>,
+
+    /// Memory limit in MiB
+    #[arg(long)]
+    pub memory: Option<u64>,
+
+    /// Set an environment variable, as KEY=VALUE. Can be repeated
+    #[arg(long, value_parser = parse_env)]
+    pub env: Vec<(String, String)>,
+
+    /// Mount an extra volume, as host:container[:options]. Can be repeated
+    #[arg(long)]
+    pub bind: Vec<String>,
+
+    /// Replace the entrypoint, split on whitespace
+    #[arg(long)]
+    pub entrypoint: Option<String>,
+}
+
+fn parse_env(arg: &str) -> Result<(String, String), String> {
+    arg.split_once('=')
+        .map(|(key, value)| (key.to_string(), value.to_string()))
+        .ok_or_else(|| format!("expected KEY=VALUE, got {arg}"))
+}
+
+impl ContainerArgs {
+    pub fn is_empty(&self) -> bool {
+        self.image.is_none()
+            && self.tag.is_none()
+            && self.hostname.is_none()
+            && self.network.is_none()
+            && self.cpus.is_none()
+            && self.memory.is_none()
+            && self.env.is_empty()
+            && self.bind.is_empty()
+            && self.entrypoint.is_none()
+    }
+
+    /// Apply the overrides to the container, returning whether any were
+    /// given.
+    pub fn apply(&self, container: &mut Container) -> bool {
+        let mut changed = false;
+        let mut set = |field: &mut Option<String>, value: &Option<String>| {
+            if let Some(value) = value {
+                *field = Some(value.clone());
+                changed = true;
+            }
+        };
+        set(&mut container.hostname, &self.hostname);
+        set(&mut container.network, &self.network);
+
+        if let Some(image) = &self.image {
+            container.image = image.clone();
+            changed = true;
+        }
+        if let Some(tag) = &self.tag {
+            container.tag = tag.clone();
+            changed = true;
+        }
+        // A pinned digest is of the image being replaced.
+        if self.image.is_some() || self.tag.is_some() {
+            container.digest = None;
+        }
+        if let Some(cpus) = self.cpus {
+            container.cpus = Some(cpus);
+            changed = true;
+        }
+        if let Some(memory) = self.memory {
+            container.memory = Some(memory);
+            changed = true;
+        }
+        if let Some(entrypoint) = &self.entrypoint {
+            container.entrypoint = Some(
+                entrypoint
+                    .split_whitespace()
+                    .map(ToString::to_string)
+                    .collect(),
+            );
+            changed = true;
+        }
+        for (key, value) in &self.env {
+            container.env.insert(key.clone(), value.clone());
+            changed = true;
+        }
+        for bind in &self.bind {
+            container.binds.push(bind.clone());
+            changed = true;
+        }
+
+        changed
+    }
+}
+
+/// A process whose memory is measured.
+#[derive(Debug, Clone, Deserialize)]
+pub struct ProcessSpec {
+    pub name: String,
+    #[serde(with = "serde_regex")]
+    pub matcher: Regex,
+    #[serde(default = "default_required")]
+    pub required: bool,
+    #[serde(default = "default_weight")]
+    pub weight: f64,
+    /// Absolute path of the process's executable. Needed to give the
+    /// process its own conf.
+    #[serde(default)]
+    pub binary: Option<String>,
+}
+
+fn default_required() -> bool {
+    true
+}
+
+fn default_weight() -> f64 {
+    1.0
+}
+
+mod serde_regex {
+    use regex::Regex;
+    use serde::{de::Error, Deserialize, Deserializer};
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Regex, D::Error> {
+        let pattern = String::deserialize(deserializer)?;
+        Regex::new(&pattern).map_err(D::Error::custom)
+    }
+}
+
+impl Experiment {
+    /// Load the experiment from the given TOML file, or the built in default.
+    pub fn load(path: Option<&str>) -> Self {
+        let contents = match path {
+            Some(path) => fs::read_to_string(path).expect("experiment file should be readable"),
+            None => DEFAULT_EXPERIMENT.to_string(),
+        };
+
+        let mut experiment: Experiment =
+            toml::from_str(&contents).unwrap_or_else(|err| panic!("invalid experiment: {err}"));
+        if let Err(err) = experiment.load.prepare() {
+            panic!("invalid load profile: {err}");
+        }
+        for generator in &experiment.generators {
+            if let Err(err) = generator.check() {
+                panic!("invalid generator: {err}");
+            }
+        }
+        experiment.hash = stable_hash(contents.as_bytes());
+        if let Some(replay) = &experiment.load.replay {
+            experiment.hash =
+                stable_hash(format!("{:016x}{:016x}", experiment.hash, replay.hash).as_bytes());
+        }
+        experiment
+    }
+}
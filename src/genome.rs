@@ -0,0 +1,89 @@
+use crate::{
+    conf::{MallocConf, RunConf},
+    schema::Schema,
+};
+
+/// How the genes are laid out: a single conf for the whole run, or the
+/// schema repeated once for each process so every process evolves its own
+/// conf.
+#[derive(Debug, Clone)]
+pub struct Genome {
+    pub schema: Schema,
+    /// The processes with their own conf, in gene order. Empty for a single
+    /// global conf.
+    pub processes: Vec<String>,
+}
+
+impl Genome {
+    pub fn global(schema: Schema) -> Self {
+        Self {
+            schema,
+            processes: Vec::new(),
+        }
+    }
+
+    pub fn per_process(schema: Schema, processes: Vec<String>) -> Self {
+        assert!(!processes.is_empty(), "per process needs some processes");
+        Self { schema, processes }
+    }
+
+    pub fn genes_size(&self) -> usize {
+        self.schema.options.len() * self.processes.len().max(1)
+    }
+
+    pub fn allele_lists(&self) -> Vec<Vec<usize>> {
+        let lists = self.schema.allele_lists();
+        (0..self.processes.len().max(1))
+            .flat_map(|_| lists.clone())
+            .collect()
+    }
+
+    /// The conf each process gets from the genes, with `None` standing for
+    /// the global conf.
+    pub fn confs(&self, genes: &[usize]) -> Result<Vec<(Option<&str>, MallocConf)>, String> {
+        if genes.len() != self.genes_size() {
+            return Err(format!(
+                "expected {} genes, got {}",
+                self.genes_size(),
+                genes.len()
+            ));
+        }
+
+        if self.processes.is_empty() {
+            return Ok(vec![(None, self.schema.decode(genes)?)]);
+        }
+
+        let size = self.schema.options.len();
+        self.processes
+            .iter()
+            .zip(genes.chunks(size).enumerate())
+            .map(|(process, (idx, genes))| {
+                let conf = self.schema.decode_at(genes, idx * size + 1)?;
+                Ok((Some(process.as_str()), conf))
+            })
+            .collect()
+    }
+
+    pub fn decode(&self, genes: &[usize]) -> Result<RunConf, String> {
+        let mut run = RunConf::default();
+        for (process, conf) in self.confs(genes)? {
+            match process {
+                Some(process) => run.overrides.push((process.to_string(), conf.to_string())),
+                None => run.global = conf.to_string(),
+            }
+        }
+        Ok(run)
+    }
+
+    /// Check every conf against the schema's conflicts.
+    pub fn check(&self, genes: &[usize]) -> Result<(), String> {
+        for (process, conf) in self.confs(genes)? {
+            self.schema.check(&conf).map_err(|reason| match process {
+                Some(process) => format!("{process}: {reason}"),
+                None => reason,
+            })?;
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,1115 @@
+use opentelemetry_proto::tonic::{
+    collector::{
+        logs::v1::{logs_service_client::LogsServiceClient, ExportLogsServiceRequest},
+        metrics::v1::{metrics_service_client::MetricsServiceClient, ExportMetricsServiceRequest},
+        trace::v1::{trace_service_client::TraceServiceClient, ExportTraceServiceRequest},
+    },
+    common::v1::{any_value, AnyValue, InstrumentationScope, KeyValue},
+    logs::v1::{LogRecord, ResourceLogs, ScopeLogs},
+    metrics::v1::{
+        metric, number_data_point, Gauge, Metric, NumberDataPoint, ResourceMetrics, ScopeMetrics,
+    },
+    resource::v1::Resource,
+    trace::v1::{span::SpanKind, ResourceSpans, ScopeSpans, Span},
+};
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use std::{
+    collections::HashMap,
+    f64::consts::TAU,
+    fs::{self, File},
+    io::{self, Write},
+    path::PathBuf,
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpStream,
+    task::yield_now,
+    time::{sleep, timeout, Duration, Instant},
+};
+use tonic::transport::Channel;
+
+/// The most sent in one go between checking the time.
+pub const BATCH: u64 = 1000;
+
+/// How often a rate limited load catches up.
+pub const TICK: Duration = Duration::from_millis(10);
+
+/// How the rate of things sent a second changes over the run, with `t`
+/// seconds since it started.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum Shape {
+    Constant {
+        rate: f64,
+    },
+    /// From one rate to another over `seconds`, the whole run by default,
+    /// then holding there.
+    Ramp {
+        from: f64,
+        to: f64,
+        seconds: Option<f64>,
+    },
+    /// `peak` for the first `length` seconds of every `period`, `base` the
+    /// rest of the time.
+    Burst {
+        base: f64,
+        peak: f64,
+        period: f64,
+        length: f64,
+    },
+    /// `high` for the first half of every `period`, `low` for the second.
+    Square {
+        low: f64,
+        high: f64,
+        period: f64,
+    },
+    /// A day squeezed into `period` seconds, starting at the `low` of the
+    /// night and rising smoothly to `high` halfway through.
+    Diurnal {
+        low: f64,
+        high: f64,
+        period: f64,
+    },
+}
+
+impl Shape {
+    fn rate(&self, t: f64, run_for: f64) -> f64 {
+        let rate = match *self {
+            Shape::Constant { rate } => rate,
+            Shape::Ramp { from, to, seconds } => {
+                let seconds = seconds.unwrap_or(run_for);
+                if t >= seconds {
+                    to
+                } else {
+                    from + (to - from) * t / seconds
+                }
+            }
+            Shape::Burst {
+                base,
+                peak,
+                period,
+                length,
+            } => {
+                if t % period < length {
+                    peak
+                } else {
+                    base
+                }
+            }
+            Shape::Square { low, high, period } => {
+                if t % period < period / 2.0 {
+                    high
+                } else {
+                    low
+                }
+            }
+            Shape::Diurnal { low, high, period } => {
+                low + (high - low) * (1.0 - (TAU * t / period).cos()) / 2.0
+            }
+        };
+        rate.max(0.0)
+    }
+
+    pub fn check(&self) -> Result<(), String> {
+        match *self {
+            Shape::Burst { period, .. }
+            | Shape::Square { period, .. }
+            | Shape::Diurnal { period, .. }
+                if period <= 0.0 =>
+            {
+                Err("the shape's period must be positive".to_string())
+            }
+            Shape::Ramp {
+                seconds: Some(seconds),
+                ..
+            } if seconds <= 0.0 => Err("the ramp must take some time".to_string()),
+            _ => Ok(()),
+        }
+    }
+}
+
+/// Spreads what is sent over the run at the shape's rate, or as fast as
+/// possible without one.
+pub struct Pacer {
+    start: Instant,
+    run_for: f64,
+    shape: Option<Shape>,
+    sent: u64,
+    /// What is due so far, the shape's rate integrated over the run. Each
+    /// step goes at the lower of its rates, so a step into a burst doesn't
+    /// send ahead of it.
+    due: f64,
+    last: f64,
+}
+
+impl Pacer {
+    pub fn new(timelimit: Duration, shape: Option<Shape>) -> Self {
+        Pacer {
+            start: Instant::now(),
+            run_for: timelimit.as_secs_f64(),
+            shape,
+            sent: 0,
+            due: 0.0,
+            last: 0.0,
+        }
+    }
+
+    /// How many to send now, waiting until some are due, or `None` once the
+    /// time is up. Only waits once caught up, so falling behind doesn't
+    /// compound.
+    pub async fn next(&mut self) -> Option<u64> {
+        loop {
+            let now = self.start.elapsed().as_secs_f64();
+            if now > self.run_for {
+                return None;
+            }
+
+            let until = match &self.shape {
+                Some(shape) => {
+                    self.due += shape
+                        .rate(self.last, self.run_for)
+                        .min(shape.rate(now, self.run_for))
+                        * (now - self.last);
+                    self.last = now;
+                    (self.due as u64).min(self.sent + BATCH)
+                }
+                None => self.sent + BATCH,
+            };
+            if until > self.sent {
+                let batch = until - self.sent;
+                self.sent = until;
+                // Sending can be all synchronous, so let the run carry on.
+                yield_now().await;
+                return Some(batch);
+            }
+            sleep(TICK).await;
+        }
+    }
+
+    /// How many have been sent, with the rate they were sent at.
+    pub fn sent(&self) -> (u64, f64) {
+        (
+            self.sent,
+            self.sent as f64 / self.start.elapsed().as_secs_f64(),
+        )
+    }
+}
+
+fn default_shape() -> Shape {
+    Shape::Constant { rate: 100.0 }
+}
+
+fn default_services() -> usize {
+    3
+}
+
+/// Synthetic APM traces sent to the trace-agent's intake as JSON, each a
+/// root span with children.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Traces {
+    #[serde(default = "default_trace_port")]
+    pub port: u16,
+    /// Traces a second.
+    #[serde(default = "default_shape")]
+    pub shape: Shape,
+    #[serde(default = "default_spans")]
+    pub spans: usize,
+    #[serde(default = "default_services")]
+    pub services: usize,
+    /// Distinct resources of each service.
+    #[serde(default = "default_resources")]
+    pub resources: usize,
+}
+
+fn default_trace_port() -> u16 {
+    8126
+}
+
+fn default_spans() -> usize {
+    5
+}
+
+fn default_resources() -> usize {
+    10
+}
+
+/// Log lines sent over TCP, to a `tcp` logs source in the agent's conf.d.
+#[derive(Debug, Clone, Deserialize)]
+pub struct LogsTcp {
+    pub port: u16,
+    /// Lines a second.
+    #[serde(default = "default_shape")]
+    pub shape: Shape,
+    #[serde(default = "default_services")]
+    pub services: usize,
+}
+
+/// Log lines appended to a file the agent tails. For a container the file
+/// has to be bound into it.
+#[derive(Debug, Clone, Deserialize)]
+pub struct LogsFile {
+    pub path: PathBuf,
+    /// Lines a second.
+    #[serde(default = "default_shape")]
+    pub shape: Shape,
+    #[serde(default = "default_services")]
+    pub services: usize,
+}
+
+/// What an OTLP generator sends.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Signal {
+    Metrics,
+    Traces,
+    Logs,
+}
+
+/// How an OTLP generator sends.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Protocol {
+    /// JSON bodies over HTTP/1.1.
+    #[default]
+    Http,
+    /// Protobuf over cleartext HTTP/2.
+    Grpc,
+}
+
+/// OTLP to the agent's OTLP receiver, on the protocol's usual port unless
+/// `port` is given.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Otlp {
+    pub port: Option<u16>,
+    #[serde(default)]
+    pub protocol: Protocol,
+    pub signal: Signal,
+    /// Data points, spans or log records a second.
+    #[serde(default = "default_shape")]
+    pub shape: Shape,
+    #[serde(default = "default_services")]
+    pub services: usize,
+    /// Distinct metric names.
+    #[serde(default = "default_metrics")]
+    pub metrics: usize,
+}
+
+impl Otlp {
+    pub fn port(&self) -> u16 {
+        self.port.unwrap_or(match self.protocol {
+            Protocol::Http => 4318,
+            Protocol::Grpc => 4317,
+        })
+    }
+}
+
+fn default_metrics() -> usize {
+    100
+}
+
+/// Load sent alongside the dogstatsd load, to exercise the agent's other
+/// pipelines.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "kebab-case")]
+pub enum Generator {
+    Traces(Traces),
+    LogsTcp(LogsTcp),
+    LogsFile(LogsFile),
+    Otlp(Otlp),
+}
+
+impl Generator {
+    /// The TCP port the workload receives it on, as `port/tcp`.
+    pub fn port(&self) -> Option<String> {
+        match self {
+            Generator::Traces(traces) => Some(traces.port),
+            Generator::LogsTcp(logs) => Some(logs.port),
+            Generator::LogsFile(_) => None,
+            Generator::Otlp(otlp) => Some(otlp.port()),
+        }
+        .map(|port| format!("{port}/tcp"))
+    }
+
+    fn shape(&self) -> &Shape {
+        match self {
+            Generator::Traces(traces) => &traces.shape,
+            Generator::LogsTcp(logs) => &logs.shape,
+            Generator::LogsFile(logs) => &logs.shape,
+            Generator::Otlp(otlp) => &otlp.shape,
+        }
+    }
+
+    fn name(&self) -> &'static str {
+        match self {
+            Generator::Traces(_) => "traces",
+            Generator::LogsTcp(_) => "logs-tcp",
+            Generator::LogsFile(_) => "logs-file",
+            Generator::Otlp(Otlp {
+                signal: Signal::Metrics,
+                ..
+            }) => "otlp metrics",
+            Generator::Otlp(Otlp {
+                signal: Signal::Traces,
+                ..
+            }) => "otlp traces",
+            Generator::Otlp(Otlp {
+                signal: Signal::Logs,
+                ..
+            }) => "otlp logs",
+        }
+    }
+
+    pub fn check(&self) -> Result<(), String> {
+        let services = match self {
+            Generator::Traces(traces) if traces.spans == 0 || traces.resources == 0 => {
+                return Err("traces need spans and resources".to_string());
+            }
+            Generator::Traces(traces) => traces.services,
+            Generator::LogsTcp(logs) => logs.services,
+            Generator::LogsFile(logs) => logs.services,
+            Generator::Otlp(otlp) if otlp.metrics == 0 => {
+                return Err("OTLP metrics need at least one name".to_string());
+            }
+            Generator::Otlp(otlp) => otlp.services,
+        };
+        if services == 0 {
+            return Err(format!("{} needs at least one service", self.name()));
+        }
+        self.shape().check()
+    }
+}
+
+fn now_nanos() -> u128 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos()
+}
+
+/// Send an HTTP/1.1 request on a new connection to the local port,
+/// returning the response status.
+async fn request(
+    port: u16,
+    method: &str,
+    path: &str,
+    headers: &[(&str, String)],
+    body: &[u8],
+) -> io::Result<u16> {
+    let mut stream = TcpStream::connect(("127.0.0.1", port)).await?;
+    let mut head = format!(
+        "{method} {path} HTTP/1.1\r\nHost: 127.0.0.1:{port}\r\nConnection: close\r\nContent-Length: {}\r\n",
+        body.len()
+    );
+    for (name, value) in headers {
+        head.push_str(&format!("{name}: {value}\r\n"));
+    }
+    head.push_str("\r\n");
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(body).await?;
+
+    let mut response = Vec::new();
+    let mut buf = [0; 1024];
+    while !response.windows(2).any(|window| window == b"\r\n") {
+        let read = timeout(Duration::from_secs(10), stream.read(&mut buf))
+            .await
+            .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "no response"))??;
+        if read == 0 {
+            break;
+        }
+        response.extend_from_slice(&buf[..read]);
+    }
+    String::from_utf8_lossy(&response)
+        .split_whitespace()
+        .nth(1)
+        .and_then(|status| status.parse().ok())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "bad response"))
+}
+
+/// A trace of the spans, in the trace-agent's v0.4 JSON.
+fn trace(traces: &Traces, rng: &mut StdRng) -> Value {
+    let trace_id = rng.gen::<u64>() >> 1;
+    let root_id = rng.gen::<u64>() >> 1;
+    let service = format!("service{}", rng.gen_range(0..traces.services));
+    let resource = format!("GET /resource{}", rng.gen_range(0..traces.resources));
+    let start = now_nanos();
+    let duration = rng.gen_range(1_000_000..50_000_000u64);
+    let spans = (0..traces.spans)
+        .map(|span| {
+            let root = span == 0;
+            json!({
+                "trace_id": trace_id,
+                "span_id": if root { root_id } else { rng.gen::<u64>() >> 1 },
+                "parent_id": if root { 0 } else { root_id },
+                "name": if root { "web.request".to_string() } else { format!("db.query{span}") },
+                "resource": resource,
+                "service": service,
+                "type": if root { "web" } else { "db" },
+                "start": start as u64,
+                "duration": if root { duration } else { duration / traces.spans as u64 },
+                "error": 0,
+                "meta": { "env": "jemopt", "http.method": "GET" },
+                "metrics": { "_sampling_priority_v1": 1 },
+            })
+        })
+        .collect::<Vec<_>>();
+    Value::Array(spans)
+}
+
+/// A log line from one of the services.
+fn log_line(services: usize, rng: &mut StdRng) -> String {
+    format!(
+        "{:.3} INFO [service{}] request {:016x} handled in {}ms user=user{}",
+        now_nanos() as f64 / 1e9,
+        rng.gen_range(0..services),
+        rng.gen::<u64>(),
+        rng.gen_range(1..500),
+        rng.gen_range(0..1000)
+    )
+}
+
+/// An OTLP JSON export request of `count` items.
+fn otlp_body(otlp: &Otlp, count: u64, rng: &mut StdRng) -> Value {
+    let resource = json!({
+        "attributes": [{
+            "key": "service.name",
+            "value": { "stringValue": format!("service{}", rng.gen_range(0..otlp.services)) },
+        }],
+    });
+    let scope = json!({ "name": "jemopt" });
+    let now = now_nanos().to_string();
+    match otlp.signal {
+        Signal::Metrics => {
+            let metrics = (0..count)
+                .map(|_| {
+                    json!({
+                        "name": format!("jemopt.metric{}", rng.gen_range(0..otlp.metrics)),
+                        "gauge": {
+                            "dataPoints": [{
+                                "asDouble": rng.gen_range(0.0..1000.0),
+                                "timeUnixNano": now,
+                            }],
+                        },
+                    })
+                })
+                .collect::<Vec<_>>();
+            json!({ "resourceMetrics": [{
+                "resource": resource,
+                "scopeMetrics": [{ "scope": scope, "metrics": metrics }],
+            }] })
+        }
+        Signal::Traces => {
+            let spans = (0..count)
+                .map(|_| {
+                    json!({
+                        "traceId": format!("{:032x}", rng.gen::<u128>()),
+                        "spanId": format!("{:016x}", rng.gen::<u64>()),
+                        "name": "jemopt.operation",
+                        "kind": 2,
+                        "startTimeUnixNano": now,
+                        "endTimeUnixNano": now,
+                    })
+                })
+                .collect::<Vec<_>>();
+            json!({ "resourceSpans": [{
+                "resource": resource,
+                "scopeSpans": [{ "scope": scope, "spans": spans }],
+            }] })
+        }
+        Signal::Logs => {
+            let records = (0..count)
+                .map(|_| {
+                    json!({
+                        "timeUnixNano": now,
+                        "severityText": "INFO",
+                        "body": { "stringValue": log_line(otlp.services, rng) },
+                    })
+                })
+                .collect::<Vec<_>>();
+            json!({ "resourceLogs": [{
+                "resource": resource,
+                "scopeLogs": [{ "scope": scope, "logRecords": records }],
+            }] })
+        }
+    }
+}
+
+fn string_value(value: String) -> Option<AnyValue> {
+    Some(AnyValue {
+        value: Some(any_value::Value::StringValue(value)),
+    })
+}
+
+/// Export `count` items over gRPC, the same as `otlp_body`, returning
+/// whether the receiver took them.
+async fn export(otlp: &Otlp, channel: &Channel, count: u64, rng: &mut StdRng) -> bool {
+    let resource = Some(Resource {
+        attributes: vec![KeyValue {
+            key: "service.name".to_string(),
+            value: string_value(format!("service{}", rng.gen_range(0..otlp.services))),
+        }],
+        ..Default::default()
+    });
+    let scope = Some(InstrumentationScope {
+        name: "jemopt".to_string(),
+        ..Default::default()
+    });
+    let now = now_nanos() as u64;
+    match otlp.signal {
+        Signal::Metrics => {
+            let metrics = (0..count)
+                .map(|_| Metric {
+                    name: format!("jemopt.metric{}", rng.gen_range(0..otlp.metrics)),
+                    data: Some(metric::Data::Gauge(Gauge {
+                        data_points: vec![NumberDataPoint {
+                            time_unix_nano: now,
+                            value: Some(number_data_point::Value::AsDouble(
+                                rng.gen_range(0.0..1000.0),
+                            )),
+                            ..Default::default()
+                        }],
+                    })),
+                    ..Default::default()
+                })
+                .collect();
+            let request = ExportMetricsServiceRequest {
+                resource_metrics: vec![ResourceMetrics {
+                    resource,
+                    scope_metrics: vec![ScopeMetrics {
+                        scope,
+                        metrics,
+                        ..Default::default()
+                    }],
+                    ..Default::default()
+                }],
+            };
+            MetricsServiceClient::new(channel.clone())
+                .export(request)
+                .await
+                .is_ok()
+        }
+        Signal::Traces => {
+            let spans = (0..count)
+                .map(|_| Span {
+                    trace_id: rng.gen::<u128>().to_be_bytes().to_vec(),
+                    span_id: rng.gen::<u64>().to_be_bytes().to_vec(),
+                    name: "jemopt.operation".to_string(),
+                    kind: SpanKind::Server.into(),
+                    start_time_unix_nano: now,
+                    end_time_unix_nano: now,
+                    ..Default::default()
+                })
+                .collect();
+            let request = ExportTraceServiceRequest {
+                resource_spans: vec![ResourceSpans {
+                    resource,
+                    scope_spans: vec![ScopeSpans {
+                        scope,
+                        spans,
+                        ..Default::default()
+                    }],
+                    ..Default::default()
+                }],
+            };
+            TraceServiceClient::new(channel.clone())
+                .export(request)
+                .await
+                .is_ok()
+        }
+        Signal::Logs => {
+            let log_records = (0..count)
+                .map(|_| LogRecord {
+                    time_unix_nano: now,
+                    severity_text: "INFO".to_string(),
+                    body: string_value(log_line(otlp.services, rng)),
+                    ..Default::default()
+                })
+                .collect();
+            let request = ExportLogsServiceRequest {
+                resource_logs: vec![ResourceLogs {
+                    resource,
+                    scope_logs: vec![ScopeLogs {
+                        scope,
+                        log_records,
+                        ..Default::default()
+                    }],
+                    ..Default::default()
+                }],
+            };
+            LogsServiceClient::new(channel.clone())
+                .export(request)
+                .await
+                .is_ok()
+        }
+    }
+}
+
+/// Send the batch, returning how many of it failed.
+async fn send(
+    generator: &Generator,
+    port: Option<u16>,
+    count: u64,
+    rng: &mut StdRng,
+    connection: &mut Option<TcpStream>,
+    channel: &mut Option<Channel>,
+) -> u64 {
+    let port = port.unwrap_or_default();
+    let sent = match generator {
+        Generator::Traces(traces) => {
+            let body = Value::Array((0..count).map(|_| trace(traces, rng)).collect());
+            request(
+                port,
+                "PUT",
+                "/v0.4/traces",
+                &[
+                    ("Content-Type", "application/json".to_string()),
+                    ("X-Datadog-Trace-Count", count.to_string()),
+                ],
+                body.to_string().as_bytes(),
+            )
+            .await
+            .is_ok_and(|status| status < 400)
+        }
+        Generator::LogsTcp(logs) => {
+            let lines = (0..count)
+                .map(|_| log_line(logs.services, rng) + "\n")
+                .collect::<String>();
+            if connection.is_none() {
+                *connection = TcpStream::connect(("127.0.0.1", port)).await.ok();
+            }
+            match connection {
+                Some(stream) => match stream.write_all(lines.as_bytes()).await {
+                    Ok(()) => true,
+                    // Reconnect for the next batch.
+                    Err(_) => {
+                        *connection = None;
+                        false
+                    }
+                },
+                None => false,
+            }
+        }
+        Generator::LogsFile(logs) => {
+            let lines = (0..count)
+                .map(|_| log_line(logs.services, rng) + "\n")
+                .collect::<String>();
+            File::options()
+                .create(true)
+                .append(true)
+                .open(&logs.path)
+                .and_then(|mut file| file.write_all(lines.as_bytes()))
+                .is_ok()
+        }
+        Generator::Otlp(otlp) if otlp.protocol == Protocol::Grpc => {
+            // Connects on the first export, and again whenever it's lost.
+            let channel = channel.get_or_insert_with(|| {
+                Channel::from_shared(format!("http://127.0.0.1:{port}"))
+                    .expect("a local address should be a valid uri")
+                    .timeout(Duration::from_secs(10))
+                    .connect_lazy()
+            });
+            export(otlp, channel, count, rng).await
+        }
+        Generator::Otlp(otlp) => {
+            let path = match otlp.signal {
+                Signal::Metrics => "/v1/metrics",
+                Signal::Traces => "/v1/traces",
+                Signal::Logs => "/v1/logs",
+            };
+            request(
+                port,
+                "POST",
+                path,
+                &[("Content-Type", "application/json".to_string())],
+                otlp_body(otlp, count, rng).to_string().as_bytes(),
+            )
+            .await
+            .is_ok_and(|status| status < 400)
+        }
+    };
+    if sent {
+        0
+    } else {
+        count
+    }
+}
+
+/// Run the generator until the time is up, sending to the port the
+/// workload's receiving port is published on.
+pub async fn generate(generator: Generator, ports: HashMap<String, u16>, timelimit: Duration) {
+    let port = generator.port().and_then(|port| ports.get(&port).copied());
+    if generator.port().is_some() && port.is_none() {
+        println!("No port to send {} to", generator.name());
+        return;
+    }
+    if let Generator::LogsFile(logs) = &generator {
+        if let Some(dir) = logs.path.parent() {
+            let _ = fs::create_dir_all(dir);
+        }
+    }
+
+    let mut rng = StdRng::seed_from_u64(0);
+    let mut connection = None;
+    let mut channel = None;
+    let mut failed = 0;
+    let mut pacer = Pacer::new(timelimit, Some(generator.shape().clone()));
+    while let Some(count) = pacer.next().await {
+        failed += send(
+            &generator,
+            port,
+            count,
+            &mut rng,
+            &mut connection,
+            &mut channel,
+        )
+        .await;
+    }
+    let (sent, rate) = pacer.sent();
+    println!(
+        "Sent {sent} {}, {rate:.0} a second, {failed} failed",
+        generator.name()
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use opentelemetry_proto::tonic::collector::{
+        logs::v1::{
+            logs_service_server::{LogsService, LogsServiceServer},
+            ExportLogsServiceResponse,
+        },
+        metrics::v1::{
+            metrics_service_server::{MetricsService, MetricsServiceServer},
+            ExportMetricsServiceResponse,
+        },
+        trace::v1::{
+            trace_service_server::{TraceService, TraceServiceServer},
+            ExportTraceServiceResponse,
+        },
+    };
+    use std::sync::{Arc, Mutex};
+    use tokio::net::TcpListener;
+    use tonic::{
+        transport::{server::TcpIncoming, Server},
+        Request, Response, Status,
+    };
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!(
+            (actual - expected).abs() < 1e-9,
+            "{actual} isn't {expected}"
+        );
+    }
+
+    #[test]
+    fn ramps_then_holds() {
+        let ramp = Shape::Ramp {
+            from: 100.0,
+            to: 300.0,
+            seconds: Some(10.0),
+        };
+        assert_eq!(ramp.rate(0.0, 60.0), 100.0);
+        assert_eq!(ramp.rate(5.0, 60.0), 200.0);
+        assert_eq!(ramp.rate(10.0, 60.0), 300.0);
+        assert_eq!(ramp.rate(30.0, 60.0), 300.0);
+
+        let whole_run = Shape::Ramp {
+            from: 0.0,
+            to: 1000.0,
+            seconds: None,
+        };
+        assert_eq!(whole_run.rate(30.0, 60.0), 500.0);
+        assert_eq!(whole_run.rate(60.0, 60.0), 1000.0);
+    }
+
+    #[test]
+    fn bursts_at_the_start_of_each_period() {
+        let burst = Shape::Burst {
+            base: 10.0,
+            peak: 1000.0,
+            period: 60.0,
+            length: 5.0,
+        };
+        assert_eq!(burst.rate(0.0, 300.0), 1000.0);
+        assert_eq!(burst.rate(4.99, 300.0), 1000.0);
+        assert_eq!(burst.rate(5.0, 300.0), 10.0);
+        assert_eq!(burst.rate(59.99, 300.0), 10.0);
+        assert_eq!(burst.rate(60.0, 300.0), 1000.0);
+    }
+
+    #[test]
+    fn squares_by_half_periods() {
+        let square = Shape::Square {
+            low: 100.0,
+            high: 5000.0,
+            period: 120.0,
+        };
+        assert_eq!(square.rate(0.0, 300.0), 5000.0);
+        assert_eq!(square.rate(59.99, 300.0), 5000.0);
+        assert_eq!(square.rate(60.0, 300.0), 100.0);
+        assert_eq!(square.rate(120.0, 300.0), 5000.0);
+    }
+
+    #[test]
+    fn peaks_halfway_through_the_day() {
+        let diurnal = Shape::Diurnal {
+            low: 100.0,
+            high: 5100.0,
+            period: 300.0,
+        };
+        assert_close(diurnal.rate(0.0, 600.0), 100.0);
+        assert_close(diurnal.rate(75.0, 600.0), 2600.0);
+        assert_close(diurnal.rate(150.0, 600.0), 5100.0);
+        assert_close(diurnal.rate(300.0, 600.0), 100.0);
+    }
+
+    #[test]
+    fn never_goes_below_zero() {
+        let ramp = Shape::Ramp {
+            from: -100.0,
+            to: 100.0,
+            seconds: None,
+        };
+        assert_eq!(ramp.rate(0.0, 10.0), 0.0);
+    }
+
+    #[test]
+    fn rejects_shapes_without_a_period() {
+        let square = Shape::Square {
+            low: 1.0,
+            high: 2.0,
+            period: 0.0,
+        };
+        assert!(square.check().is_err());
+        let ramp = Shape::Ramp {
+            from: 1.0,
+            to: 2.0,
+            seconds: Some(0.0),
+        };
+        assert!(ramp.check().is_err());
+    }
+
+    /// Run the pacer to the end, checking every batch against what the
+    /// shape allows by then. Returns the total sent.
+    async fn pace(shape: Shape, seconds: u64) -> u64 {
+        let run_for = seconds as f64;
+        let mut pacer = Pacer::new(Duration::from_secs(seconds), Some(shape.clone()));
+        let mut total = 0;
+        // The shape's integral by the trapezoid rule, finer than the pacer.
+        let due = |until: f64| {
+            let steps = 10_000;
+            (0..steps)
+                .map(|step| {
+                    let (a, b) = (
+                        until * step as f64 / steps as f64,
+                        until * (step + 1) as f64 / steps as f64,
+                    );
+                    (shape.rate(a, run_for) + shape.rate(b, run_for)) / 2.0 * (b - a)
+                })
+                .sum::<f64>()
+        };
+        while let Some(count) = pacer.next().await {
+            assert!(count <= BATCH, "sent {count} at once");
+            total += count;
+            let now = pacer.start.elapsed().as_secs_f64();
+            assert!(
+                total as f64 <= due(now) + 1.0,
+                "{total} sent by {now}s, ahead of {}",
+                due(now)
+            );
+        }
+        assert_eq!(pacer.sent().0, total);
+        total
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn paces_a_constant_rate() {
+        let total = pace(Shape::Constant { rate: 1000.0 }, 5).await;
+        assert!((4990..=5000).contains(&total), "sent {total}");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn paces_a_ramp() {
+        let ramp = Shape::Ramp {
+            from: 0.0,
+            to: 2000.0,
+            seconds: None,
+        };
+        let total = pace(ramp, 4).await;
+        assert!((3950..=4000).contains(&total), "sent {total}");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn paces_bursts() {
+        let burst = Shape::Burst {
+            base: 0.0,
+            peak: 10_000.0,
+            period: 2.0,
+            length: 0.5,
+        };
+        // Two bursts of half a second, each losing up to a tick at its edges.
+        let total = pace(burst, 4).await;
+        assert!((9600..=10_000).contains(&total), "sent {total}");
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn caps_batches_when_the_rate_runs_ahead() {
+        let mut pacer = Pacer::new(Duration::from_secs(1), Some(Shape::Constant { rate: 1e6 }));
+        // A hundred thousand are due by now, but only a batch goes at once.
+        sleep(Duration::from_millis(100)).await;
+        assert_eq!(pacer.next().await, Some(BATCH));
+        assert_eq!(pacer.next().await, Some(BATCH));
+        assert!(pace(Shape::Constant { rate: 1e6 }, 1).await > 0);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn sends_batches_without_a_shape() {
+        let mut pacer = Pacer::new(Duration::from_secs(1), None);
+        assert_eq!(pacer.next().await, Some(BATCH));
+        assert_eq!(pacer.next().await, Some(BATCH));
+    }
+
+    /// An OTLP gRPC receiver, keeping the name of every metric and span and
+    /// the body of every log record it's sent.
+    #[derive(Clone, Default)]
+    struct Receiver(Arc<Mutex<Vec<String>>>);
+
+    #[tonic::async_trait]
+    impl MetricsService for Receiver {
+        async fn export(
+            &self,
+            request: Request<ExportMetricsServiceRequest>,
+        ) -> Result<Response<ExportMetricsServiceResponse>, Status> {
+            let names = request
+                .into_inner()
+                .resource_metrics
+                .into_iter()
+                .flat_map(|resource| resource.scope_metrics)
+                .flat_map(|scope| scope.metrics)
+                .map(|metric| metric.name);
+            self.0.lock().unwrap().extend(names);
+            Ok(Response::new(ExportMetricsServiceResponse::default()))
+        }
+    }
+
+    #[tonic::async_trait]
+    impl TraceService for Receiver {
+        async fn export(
+            &self,
+            request: Request<ExportTraceServiceRequest>,
+        ) -> Result<Response<ExportTraceServiceResponse>, Status> {
+            let names = request
+                .into_inner()
+                .resource_spans
+                .into_iter()
+                .flat_map(|resource| resource.scope_spans)
+                .flat_map(|scope| scope.spans)
+                .map(|span| span.name);
+            self.0.lock().unwrap().extend(names);
+            Ok(Response::new(ExportTraceServiceResponse::default()))
+        }
+    }
+
+    #[tonic::async_trait]
+    impl LogsService for Receiver {
+        async fn export(
+            &self,
+            request: Request<ExportLogsServiceRequest>,
+        ) -> Result<Response<ExportLogsServiceResponse>, Status> {
+            let bodies = request
+                .into_inner()
+                .resource_logs
+                .into_iter()
+                .flat_map(|resource| resource.scope_logs)
+                .flat_map(|scope| scope.log_records)
+                .filter_map(|record| match record.body?.value? {
+                    any_value::Value::StringValue(body) => Some(body),
+                    _ => None,
+                });
+            self.0.lock().unwrap().extend(bodies);
+            Ok(Response::new(ExportLogsServiceResponse::default()))
+        }
+    }
+
+    async fn receive() -> (u16, Receiver) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let receiver = Receiver::default();
+        let incoming = TcpIncoming::from_listener(listener, true, None).unwrap();
+        tokio::spawn(
+            Server::builder()
+                .add_service(MetricsServiceServer::new(receiver.clone()))
+                .add_service(TraceServiceServer::new(receiver.clone()))
+                .add_service(LogsServiceServer::new(receiver.clone()))
+                .serve_with_incoming(incoming),
+        );
+        (port, receiver)
+    }
+
+    fn grpc(signal: &str) -> Generator {
+        toml::from_str(&format!(
+            "type = \"otlp\"\nprotocol = \"grpc\"\nsignal = \"{signal}\""
+        ))
+        .unwrap()
+    }
+
+    #[tokio::test]
+    async fn exports_each_signal_over_grpc() {
+        let (port, receiver) = receive().await;
+        let mut rng = StdRng::seed_from_u64(0);
+        // Big enough to need more than HTTP/2's initial flow control window.
+        for (signal, count) in [("metrics", BATCH), ("traces", BATCH), ("logs", 3)] {
+            let failed = send(
+                &grpc(signal),
+                Some(port),
+                count,
+                &mut rng,
+                &mut None,
+                &mut None,
+            )
+            .await;
+            assert_eq!(failed, 0, "{signal} failed");
+        }
+
+        let received = receiver.0.lock().unwrap();
+        let count = |f: fn(&String) -> bool| received.iter().filter(|item| f(item)).count();
+        assert_eq!(received.len(), 2 * BATCH as usize + 3);
+        assert_eq!(
+            count(|name| name.starts_with("jemopt.metric")),
+            BATCH as usize
+        );
+        assert_eq!(count(|name| name == "jemopt.operation"), BATCH as usize);
+        assert_eq!(count(|body| body.contains(" INFO [service")), 3);
+    }
+
+    #[tokio::test]
+    async fn sends_every_batch_over_one_channel() {
+        let (port, receiver) = receive().await;
+        let mut rng = StdRng::seed_from_u64(0);
+        let mut channel = None;
+        for _ in 0..3 {
+            let generator = grpc("traces");
+            let failed = send(&generator, Some(port), 2, &mut rng, &mut None, &mut channel).await;
+            assert_eq!(failed, 0);
+        }
+        assert!(channel.is_some());
+        assert_eq!(receiver.0.lock().unwrap().len(), 6);
+    }
+
+    #[tokio::test]
+    async fn counts_exports_nothing_received_as_failed() {
+        // A port that was free a moment ago.
+        let port = TcpListener::bind("127.0.0.1:0")
+            .await
+            .unwrap()
+            .local_addr()
+            .unwrap()
+            .port();
+        let mut rng = StdRng::seed_from_u64(0);
+        let failed = send(&grpc("logs"), Some(port), 5, &mut rng, &mut None, &mut None).await;
+        assert_eq!(failed, 5);
+    }
+}
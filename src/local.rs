@@ -0,0 +1,490 @@
+use rand::{distributions::Alphanumeric, Rng};
+use std::{
+    collections::HashMap,
+    ffi::OsStr,
+    fs, io,
+    path::{Path, PathBuf},
+    process::Stdio,
+    time::Duration,
+};
+use tokio::process::{Child, Command};
+
+use crate::{
+    cleanup::{Guard, Resource},
+    conf::RunConf,
+    error::RunError,
+    experiment::{CommandTarget, LocalProcess, ProcessSpec},
+    memory::Snapshot,
+    target::{self, Target},
+};
+
+/// A workload started on this machine, in its own process group.
+pub struct Running {
+    child: Child,
+    pid: u32,
+    cgroup: Option<Cgroup>,
+    /// Where the workload's stdout and stderr go.
+    log: PathBuf,
+    guard: Guard,
+}
+
+/// A random name for a run's files.
+fn run_id() -> String {
+    let mut rng = rand::thread_rng();
+    format!(
+        "run-{}",
+        (0..10)
+            .map(|_| rng.sample(Alphanumeric) as char)
+            .collect::<String>()
+    )
+}
+
+/// A cgroup v2 made for one run.
+struct Cgroup {
+    path: PathBuf,
+    guard: Guard,
+}
+
+impl Cgroup {
+    /// Make a new cgroup under the parent with the limits. Controllers are
+    /// enabled for the parent's children, so the parent can't hold any
+    /// processes itself.
+    fn create(parent: &Path, cpus: Option<f64>, memory: Option<u64>) -> io::Result<Self> {
+        fs::create_dir_all(parent)?;
+        // Without the memory controller the cgroup figures are zero, but the
+        // run can still go ahead.
+        let _ = fs::write(parent.join("cgroup.subtree_control"), "+memory");
+        if cpus.is_some() {
+            fs::write(parent.join("cgroup.subtree_control"), "+cpu")?;
+        }
+
+        let path = parent.join(run_id());
+        fs::create_dir(&path)?;
+        let cgroup = Cgroup {
+            guard: Guard::new(Resource::Cgroup(path.clone())),
+            path,
+        };
+
+        if let Some(mib) = memory {
+            fs::write(
+                cgroup.path.join("memory.max"),
+                (mib * 1024 * 1024).to_string(),
+            )?;
+        }
+        if let Some(cpus) = cpus {
+            fs::write(
+                cgroup.path.join("cpu.max"),
+                format!("{} 100000", (cpus * 100_000.0) as u64),
+            )?;
+        }
+        Ok(cgroup)
+    }
+
+    fn pids(&self) -> Vec<u32> {
+        fs::read_to_string(self.path.join("cgroup.procs"))
+            .unwrap_or_default()
+            .lines()
+            .filter_map(|pid| pid.parse().ok())
+            .collect()
+    }
+
+    /// `memory.current` followed by `memory.stat`, as the snapshot script
+    /// prints them.
+    fn memory(&self) -> String {
+        ["memory.current", "memory.stat"]
+            .iter()
+            .map(|file| fs::read_to_string(self.path.join(file)).unwrap_or_default())
+            .collect()
+    }
+
+    /// Whether the kernel's OOM killer has killed anything in the cgroup.
+    fn oom_killed(&self) -> bool {
+        fs::read_to_string(self.path.join("memory.events"))
+            .unwrap_or_default()
+            .lines()
+            .filter_map(|line| line.strip_prefix("oom_kill "))
+            .any(|count| count.trim() != "0")
+    }
+
+    /// Kill anything left in the cgroup and remove it.
+    async fn remove(self) {
+        let _ = fs::write(self.path.join("cgroup.kill"), "1");
+        for _ in 0..50 {
+            if self.pids().is_empty() {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        match fs::remove_dir(&self.path) {
+            Ok(()) => self.guard.release(),
+            // Dropping the guard tries again.
+            Err(err) => println!("Failed to remove cgroup {}: {err}", self.path.display()),
+        }
+    }
+}
+
+/// Replace `{config}` with the config path. Fails if a config was given
+/// that nothing uses.
+fn with_config(args: &[String], config: Option<&PathBuf>) -> Result<Vec<String>, RunError> {
+    let Some(config) = config else {
+        return Ok(args.to_vec());
+    };
+
+    if !args.iter().any(|arg| arg.contains("{config}")) {
+        return Err(RunError::Setup(
+            "the target has no {config} to put the config in".to_string(),
+        ));
+    }
+    Ok(args
+        .iter()
+        .map(|arg| arg.replace("{config}", &config.display().to_string()))
+        .collect())
+}
+
+/// Run the command through another, which execs it once it has set things
+/// up.
+fn wrap(command: Command, program: &str, args: &[&OsStr]) -> Command {
+    let inner = command.as_std();
+    let mut wrapped = Command::new(program);
+    wrapped
+        .args(args)
+        .arg(inner.get_program())
+        .args(inner.get_args())
+        .envs(
+            inner
+                .get_envs()
+                .filter_map(|(key, value)| value.map(|value| (key, value))),
+        );
+    wrapped
+}
+
+/// Spawn the command with the conf and its output appended to the log, in a
+/// new process group so the whole
+/// workload can be stopped together. It's moved into the cgroup and pinned
+/// to the cores before it execs, so everything it starts is too.
+fn spawn(
+    mut command: Command,
+    conf: &RunConf,
+    preload: &[String],
+    cgroup: Option<Cgroup>,
+    cpuset: Option<&str>,
+    log: &Path,
+) -> Result<Running, RunError> {
+    if !conf.overrides.is_empty() {
+        return Err(RunError::Setup(
+            "per process confs need a docker target".to_string(),
+        ));
+    }
+
+    if let Some(cpuset) = cpuset {
+        command = wrap(command, "taskset", &["-c".as_ref(), cpuset.as_ref()]);
+    }
+    if let Some(cgroup) = &cgroup {
+        let procs = cgroup.path.join("cgroup.procs");
+        command = wrap(
+            command,
+            "sh",
+            &[
+                "-c".as_ref(),
+                r#"echo $$ > "$0" && exec "$@""#.as_ref(),
+                procs.as_os_str(),
+            ],
+        );
+    }
+
+    let output = fs::File::options()
+        .append(true)
+        .open(log)
+        .and_then(|file| Ok((file.try_clone()?, file)))
+        .map_err(|err| RunError::Setup(format!("failed to open {}: {err}", log.display())))?;
+
+    command
+        .envs(target::malloc_env(conf, preload))
+        .stdin(Stdio::null())
+        .stdout(output.0)
+        .stderr(output.1)
+        .process_group(0)
+        .kill_on_drop(true);
+
+    match command.spawn() {
+        Ok(child) => {
+            let pid = child.id().expect("just spawned");
+            println!("Process {pid} running with {:?}", conf.to_string());
+            Ok(Running {
+                child,
+                pid,
+                cgroup,
+                log: log.to_path_buf(),
+                guard: Guard::new(Resource::ProcessGroup(pid)),
+            })
+        }
+        Err(err) => Err(RunError::Setup(format!(
+            "failed to spawn the target: {err}"
+        ))),
+    }
+}
+
+/// The workload listens on this machine, so each port is its own.
+fn local_ports(ports: &[String]) -> HashMap<String, u16> {
+    ports
+        .iter()
+        .filter_map(|port| Some((port.clone(), port.split('/').next()?.parse().ok()?)))
+        .collect()
+}
+
+/// Terminate the process group, killing it if it hasn't exited after a
+/// while.
+async fn terminate(mut running: Running) {
+    let group = format!("-{}", running.pid);
+    let _ = Command::new("kill")
+        .args(["-TERM", "--", &group])
+        .stderr(Stdio::null())
+        .status()
+        .await;
+    if tokio::time::timeout(Duration::from_secs(10), running.child.wait())
+        .await
+        .is_err()
+    {
+        let _ = Command::new("kill")
+            .args(["-KILL", "--", &group])
+            .stderr(Stdio::null())
+            .status()
+            .await;
+        let _ = running.child.wait().await;
+    }
+    running.guard.release();
+    if let Some(cgroup) = running.cgroup {
+        cgroup.remove().await;
+    }
+}
+
+/// Why the workload crashed, if it has. Commands may launch something and
+/// exit, so only a failed launch counts for them.
+fn crashed(running: &mut Running, exit_ok: bool) -> Option<String> {
+    if running.cgroup.as_ref().is_some_and(Cgroup::oom_killed) {
+        return Some("OOM killed".to_string());
+    }
+    match running.child.try_wait() {
+        Ok(Some(status)) if !(exit_ok && status.success()) => Some(format!("exited with {status}")),
+        _ => None,
+    }
+}
+
+/// The last lines the workload wrote.
+fn log_tail(running: &Running) -> String {
+    let log = fs::read_to_string(&running.log).unwrap_or_default();
+    let lines = log.lines().collect::<Vec<_>>();
+    lines[lines.len().saturating_sub(target::LOG_TAIL)..].join("\n")
+}
+
+/// The pid and all its descendants.
+fn process_tree(root: u32) -> Vec<u32> {
+    let mut children: HashMap<u32, Vec<u32>> = HashMap::new();
+    for entry in fs::read_dir("/proc").into_iter().flatten().flatten() {
+        let Some(pid) = entry.file_name().to_str().and_then(|pid| pid.parse().ok()) else {
+            continue;
+        };
+        // The command name in brackets can hold spaces, so the parent pid
+        // is found after its closing bracket.
+        let Ok(stat) = fs::read_to_string(entry.path().join("stat")) else {
+            continue;
+        };
+        let Some(ppid) = stat
+            .rsplit_once(')')
+            .and_then(|(_, rest)| rest.split_whitespace().nth(1))
+            .and_then(|ppid| ppid.parse::<u32>().ok())
+        else {
+            continue;
+        };
+        children.entry(ppid).or_default().push(pid);
+    }
+
+    let mut tree = vec![root];
+    let mut next = 0;
+    while let Some(pid) = tree.get(next).copied() {
+        tree.extend(children.get(&pid).into_iter().flatten());
+        next += 1;
+    }
+    tree
+}
+
+/// Whether the workload's first process has exited, leaving at most a
+/// zombie.
+fn exited(pid: u32) -> bool {
+    fs::read_to_string(format!("/proc/{pid}/stat"))
+        .ok()
+        .and_then(|stat| {
+            stat.rsplit_once(')')
+                .and_then(|(_, rest)| rest.split_whitespace().next())
+                .map(|state| state == "Z")
+        })
+        .unwrap_or(true)
+}
+
+/// The snapshot script's output for the workload, read straight from /proc
+/// and its cgroup.
+fn snapshot_output(running: &Running) -> Result<String, RunError> {
+    if exited(running.pid) {
+        return Err(RunError::ContainerExited(format!(
+            "process {} exited",
+            running.pid
+        )));
+    }
+
+    let pids = match &running.cgroup {
+        Some(cgroup) => cgroup.pids(),
+        None => process_tree(running.pid),
+    };
+
+    let mut output = String::new();
+    for pid in pids {
+        let (Ok(cmdline), Ok(smaps)) = (
+            fs::read(format!("/proc/{pid}/cmdline")),
+            fs::read_to_string(format!("/proc/{pid}/smaps_rollup")),
+        ) else {
+            continue;
+        };
+        output.push_str(&format!("== pid {pid}\n"));
+        output.push_str("== process ");
+        output.push_str(&String::from_utf8_lossy(&cmdline).replace('\0', " "));
+        output.push('\n');
+        output.push_str(&smaps);
+    }
+    output.push_str("== cgroup\n");
+    if let Some(cgroup) = &running.cgroup {
+        output.push_str(&cgroup.memory());
+    }
+    Ok(output)
+}
+
+impl Target for LocalProcess {
+    type Running = Running;
+
+    async fn launch(
+        &self,
+        conf: &RunConf,
+        config: Option<&PathBuf>,
+        _processes: &[ProcessSpec],
+        log: &Path,
+        ports: &[String],
+    ) -> Result<(Running, HashMap<String, u16>), RunError> {
+        let mut command = Command::new(&self.binary);
+        command
+            .args(with_config(&self.args, config)?)
+            .envs(&self.env);
+
+        let cgroup = match &self.cgroup {
+            Some(parent) => Some(Cgroup::create(parent, self.cpus, self.memory).map_err(
+                |err| {
+                    RunError::Setup(format!(
+                        "failed to create a cgroup in {}: {err}",
+                        parent.display()
+                    ))
+                },
+            )?),
+            None if self.cpus.is_some() || self.memory.is_some() => {
+                return Err(RunError::Setup(
+                    "CPU and memory limits need a cgroup".to_string(),
+                ));
+            }
+            None => None,
+        };
+        spawn(
+            command,
+            conf,
+            &self.preload,
+            cgroup,
+            self.cpuset.as_deref(),
+            log,
+        )
+        .map(|running| (running, local_ports(ports)))
+    }
+
+    async fn snapshot(
+        &self,
+        running: &Running,
+        elapsed: f64,
+        processes: &[ProcessSpec],
+    ) -> Result<Snapshot, RunError> {
+        Snapshot::parse(&snapshot_output(running)?, elapsed, processes)
+    }
+
+    async fn crashed(&self, running: &mut Running) -> Option<String> {
+        crashed(running, false)
+    }
+
+    async fn logs(&self, running: &Running) -> String {
+        log_tail(running)
+    }
+
+    async fn stop(&self, running: Running) {
+        terminate(running).await;
+    }
+}
+
+impl Target for CommandTarget {
+    type Running = Running;
+
+    async fn launch(
+        &self,
+        conf: &RunConf,
+        config: Option<&PathBuf>,
+        _processes: &[ProcessSpec],
+        log: &Path,
+        ports: &[String],
+    ) -> Result<(Running, HashMap<String, u16>), RunError> {
+        let launch = with_config(&[self.launch.clone()], config)?.remove(0);
+        let mut command = Command::new("sh");
+        command.args(["-c", &launch]).envs(&self.env);
+        spawn(
+            command,
+            conf,
+            &self.preload,
+            None,
+            self.cpuset.as_deref(),
+            log,
+        )
+        .map(|running| (running, local_ports(ports)))
+    }
+
+    async fn snapshot(
+        &self,
+        running: &Running,
+        elapsed: f64,
+        processes: &[ProcessSpec],
+    ) -> Result<Snapshot, RunError> {
+        let output = match &self.snapshot {
+            Some(snapshot) => {
+                let output = Command::new("sh")
+                    .args(["-c", snapshot])
+                    .output()
+                    .await
+                    .map_err(|err| RunError::MeasurementFailed(err.to_string()))?;
+                if !output.status.success() {
+                    return Err(RunError::MeasurementFailed(format!(
+                        "the snapshot command failed with {}",
+                        output.status
+                    )));
+                }
+                String::from_utf8_lossy(&output.stdout).into_owned()
+            }
+            None => snapshot_output(running)?,
+        };
+        Snapshot::parse(&output, elapsed, processes)
+    }
+
+    async fn crashed(&self, running: &mut Running) -> Option<String> {
+        crashed(running, self.snapshot.is_some())
+    }
+
+    async fn logs(&self, running: &Running) -> String {
+        log_tail(running)
+    }
+
+    async fn stop(&self, running: Running) {
+        if let Some(stop) = &self.stop {
+            let _ = Command::new("sh").args(["-c", stop]).status().await;
+        }
+        terminate(running).await;
+    }
+}
@@ -1,10 +1,42 @@
 use clap::{Parser, Subcommand};
 use genetic_algorithm::strategy::evolve::prelude::*;
 
-mod agent;
+mod cache;
+mod capture;
+mod checkpoint;
+mod cleanup;
+mod conf;
+mod docker;
 mod dogstatsd;
+mod error;
+mod experiment;
+mod genome;
+mod load;
+mod local;
+mod measure;
+mod memory;
+mod scheduler;
+mod schema;
+mod stats;
+mod target;
 
-const RUN_FOR_SECONDS: u64 = 60;
+use cache::{Cache, SharedCache};
+use checkpoint::{Checkpoint, CheckpointReporter, Resume};
+use conf::RunConf;
+use error::{Failures, RunError, SharedFailures};
+use experiment::Experiment;
+use genome::Genome;
+use measure::Measure;
+use schema::Schema;
+use stats::Summary;
+use std::{
+    path::PathBuf,
+    sync::{Arc, Mutex},
+};
+
+const STATE_FILE: &str = "jemopt-state.json";
+
+const CACHE_FILE: &str = "jemopt-cache.json";
 
 #[derive(Parser)]
 struct Args {
@@ -16,23 +48,67 @@ struct Args {
 enum Commands {
     /// Run the GA.
     Evolve {
-        /// Time in seconds to run for
-        #[arg(short, long, default_value_t = RUN_FOR_SECONDS)]
-        seconds: u64,
+        #[command(flatten)]
+        measure: Measure,
 
-        /// Send payloads via dogstatsd while running
-        #[arg(short, long)]
-        payloads: bool,
+        /// The jemalloc option schema to search. Defaults to the built in schema
+        #[arg(long)]
+        schema: Option<String>,
 
-        /// The config file to use
-        #[arg(short, long)]
-        config: Option<String>,
+        /// Evolve a separate conf for each process in the experiment rather
+        /// than one conf for them all. Every process needs a `binary`
+        #[arg(long)]
+        per_process: bool,
+
+        /// Give the processes that aren't required their own conf too, when
+        /// evolving per process. Otherwise they run with jemalloc's defaults
+        #[arg(long, requires = "per_process")]
+        optional_processes: bool,
+
+        /// Where to write the state after every generation. Defaults to the
+        /// resumed file, or jemopt-state.json
+        #[arg(long)]
+        state: Option<String>,
+
+        /// Carry on from a previously written state file
+        #[arg(long)]
+        resume: Option<String>,
+
+        /// The file that caches measurements between invocations
+        #[arg(long, default_value = CACHE_FILE)]
+        cache: String,
     },
     /// Interpret the gene results from the evolution.
     Interpret {
         /// genes in CSV
         #[arg(short, long)]
         genes: String,
+
+        /// The jemalloc option schema the genes were evolved with
+        #[arg(long)]
+        schema: Option<String>,
+
+        /// The genes hold a conf for each process in the experiment
+        #[arg(long)]
+        per_process: bool,
+
+        /// The per process genes include the processes that aren't required
+        #[arg(long, requires = "per_process")]
+        optional_processes: bool,
+
+        /// The experiment the per process genes were evolved with
+        #[arg(short, long)]
+        experiment: Option<String>,
+    },
+    /// Parse a MALLOC_CONF string into the nearest genes.
+    Parse {
+        /// The jemalloc conf to parse
+        #[arg(short, long)]
+        jemalloc: String,
+
+        /// The jemalloc option schema to encode the conf with
+        #[arg(long)]
+        schema: Option<String>,
     },
     /// Run the agent with the given conf
     Run {
@@ -40,81 +116,351 @@ enum Commands {
         #[arg(short, long, default_value_t = String::new())]
         jemalloc: String,
 
-        /// Time in seconds to run for
-        #[arg(short, long, default_value_t = RUN_FOR_SECONDS)]
-        seconds: u64,
+        /// Give a process its own conf, as name=conf. Can be repeated
+        #[arg(long = "process-conf", value_parser = parse_process_conf)]
+        process_confs: Vec<(String, String)>,
+
+        #[command(flatten)]
+        measure: Measure,
+
+        /// The file that caches measurements between invocations
+        #[arg(long, default_value = CACHE_FILE)]
+        cache: String,
+
+        /// Run every trial even if the cache already has samples for this
+        /// conf. The new samples are added to the cache
+        #[arg(long)]
+        fresh: bool,
+
+        /// Write the memory time series of the trials to this CSV file
+        #[arg(long)]
+        series: Option<String>,
+    },
+    /// Remove the containers jemopt left behind, found by their label.
+    /// Those of jemopts that are still running are kept.
+    Cleanup {
+        /// Remove the containers of running jemopts too
+        #[arg(long)]
+        all: bool,
+    },
+    /// Record dogstatsd traffic into a capture that `[load.replay]` can
+    /// send to the agent.
+    Capture {
+        /// The UDP address to receive the traffic on
+        #[arg(short, long, default_value = "127.0.0.1:8125")]
+        listen: String,
 
-        /// Send payloads via dogstatsd while running
+        /// The capture file to write
         #[arg(short, long)]
-        payloads: bool,
+        output: PathBuf,
 
-        /// The config file to use
+        /// Stop after this many seconds. Runs until interrupted otherwise
         #[arg(short, long)]
-        config: Option<String>,
+        seconds: Option<u64>,
     },
 }
 
 fn main() {
     let cli = Args::parse();
+    cleanup::install();
 
     match cli.command {
         Commands::Evolve {
-            seconds,
-            payloads,
-            config,
-        } => evolution(seconds, payloads, config),
-        Commands::Interpret { genes } => interpret(genes),
+            measure,
+            schema,
+            per_process,
+            optional_processes,
+            state,
+            resume,
+            cache,
+        } => {
+            let state = state
+                .or_else(|| resume.clone())
+                .unwrap_or_else(|| STATE_FILE.to_string());
+            let resume = resume.as_deref().map(Checkpoint::load);
+            let experiment = measure.experiment();
+            let genome = genome(
+                Schema::load(schema.as_deref()),
+                per_process,
+                optional_processes,
+                &experiment,
+            );
+            evolution(
+                measure,
+                experiment,
+                genome,
+                PathBuf::from(state),
+                resume,
+                Cache::load(PathBuf::from(cache)),
+            )
+        }
+        Commands::Interpret {
+            genes,
+            schema,
+            per_process,
+            optional_processes,
+            experiment,
+        } => interpret(
+            genes,
+            genome(
+                Schema::load(schema.as_deref()),
+                per_process,
+                optional_processes,
+                &Experiment::load(experiment.as_deref()),
+            ),
+        ),
+        Commands::Parse { jemalloc, schema } => parse(&jemalloc, Schema::load(schema.as_deref())),
         Commands::Run {
             jemalloc,
+            process_confs,
+            measure,
+            cache,
+            fresh,
+            series,
+        } => run(
+            &RunConf {
+                global: jemalloc,
+                overrides: process_confs,
+            },
+            measure,
+            Arc::new(Mutex::new(Cache::load(PathBuf::from(cache)))),
+            fresh,
+            series,
+        ),
+        Commands::Cleanup { all } => tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(cleanup::cleanup(all)),
+        Commands::Capture {
+            listen,
+            output,
             seconds,
-            payloads,
-            config,
-        } => run(&jemalloc, seconds, payloads, config),
+        } => tokio::runtime::Runtime::new()
+            .unwrap()
+            .block_on(capture::capture(&listen, &output, seconds))
+            .expect("should be able to capture"),
     }
 }
 
-fn run(conf: &str, seconds: u64, payloads: bool, config: Option<String>) {
-    match tokio::runtime::Runtime::new()
-        .unwrap()
-        .block_on(agent::run_container_with_conf_string(
-            conf,
-            seconds,
-            payloads,
-            config.as_ref().map(|c| c.as_str()),
-        )) {
-        Some(rss) => println!("RSS: {rss:?}"),
+/// Parse a `name=conf` argument.
+fn parse_process_conf(arg: &str) -> Result<(String, String), String> {
+    let (name, conf) = arg
+        .split_once('=')
+        .ok_or_else(|| format!("expected name=conf, got {arg}"))?;
+    conf.parse::<conf::MallocConf>()
+        .map_err(|err| err.to_string())?;
+    Ok((name.to_string(), conf.to_string()))
+}
+
+/// Lay the genes out for one conf, or one conf for each of the experiment's
+/// processes, leaving out the optional ones unless asked for.
+fn genome(
+    schema: Schema,
+    per_process: bool,
+    optional_processes: bool,
+    experiment: &Experiment,
+) -> Genome {
+    if !per_process {
+        return Genome::global(schema);
+    }
+
+    if !matches!(experiment.target, experiment::TargetSpec::Docker(_)) {
+        panic!("per process confs need a docker target");
+    }
+
+    let processes = experiment
+        .processes
+        .iter()
+        .filter(|process| process.required || optional_processes)
+        .collect::<Vec<_>>();
+    if let Some(process) = processes.iter().find(|process| process.binary.is_none()) {
+        panic!(
+            "process {} needs a binary for per process confs",
+            process.name
+        );
+    }
+    Genome::per_process(
+        schema,
+        processes
+            .iter()
+            .map(|process| process.name.clone())
+            .collect(),
+    )
+}
+
+fn run(conf: &RunConf, measure: Measure, cache: SharedCache, fresh: bool, series: Option<String>) {
+    let mut experiment = measure.experiment();
+    measure.prepare(&mut experiment);
+    measure.schedule(&experiment);
+    let failures = Arc::new(Mutex::new(Failures::default()));
+    let (samples, stats) = match measure.samples(conf, &experiment, &cache, fresh, &failures) {
+        Ok(samples) => samples,
+        Err(err) => {
+            println!("Run failed: {err}");
+            println!("Failures: {}", failures.lock().unwrap());
+            return;
+        }
+    };
+
+    if let Some(stats) = stats {
+        let totals = stats.totals(
+            measure.source,
+            &experiment.processes,
+            measure.metric,
+            measure.warmup as f64,
+        );
+        for (trial, (total, series)) in totals.iter().zip(stats.series()).enumerate() {
+            println!(
+                "Trial {}: {:?} {total} ({:?}) over {} samples, final {:?}",
+                trial + 1,
+                measure.source,
+                measure.metric,
+                series.len(),
+                series.last()
+            );
+        }
+
+        if let Some(path) = series {
+            let mut csv = String::from(
+                "trial,elapsed,process,rss,pss,private_dirty,anonymous,anon_huge_pages\n",
+            );
+            for (trial, series) in stats.series().iter().enumerate() {
+                for snapshot in series {
+                    for (process, memory) in &snapshot.processes {
+                        csv.push_str(&format!(
+                            "{},{:.3},{process},{},{},{},{},{}\n",
+                            trial + 1,
+                            snapshot.elapsed,
+                            memory.rss,
+                            memory.pss,
+                            memory.private_dirty,
+                            memory.anonymous,
+                            memory.anon_huge_pages,
+                        ));
+                    }
+                    csv.push_str(&format!(
+                        "{},{:.3},cgroup,{},,,{},\n",
+                        trial + 1,
+                        snapshot.elapsed,
+                        snapshot.cgroup.current,
+                        snapshot.cgroup.anon,
+                    ));
+                }
+            }
+            std::fs::write(&path, csv).expect("series file should be writable");
+            println!("Wrote time series to {path}");
+        }
+    }
+
+    match Summary::new(&samples) {
+        Some(summary) => {
+            println!("Samples: {samples:?}");
+            println!("Summary: {summary}");
+            println!(
+                "Score ({:?}): {:.0}",
+                measure.aggregate,
+                summary.aggregate(measure.aggregate, measure.stddevs)
+            );
+        }
         None => println!("Duff run"),
     }
+    println!("Failures: {}", failures.lock().unwrap());
 }
 
 /// Interpret the genes
-fn interpret(genes: String) {
+fn interpret(genes: String, genome: Genome) {
     let genes = genes
         .split(",")
         .map(|gene| gene.parse::<usize>().expect("gene should be a number"))
         .collect::<Vec<_>>();
+    let confs = match genome.confs(&genes) {
+        Ok(confs) => confs,
+        Err(err) => {
+            println!("Invalid genes: {err}");
+            return;
+        }
+    };
 
-    let conf = agent::MallocConf::from(genes.as_ref());
-    println!("{}", conf.to_string());
+    for (process, conf) in confs {
+        match process {
+            Some(process) => println!("{process}: {conf}"),
+            None => println!("{conf}"),
+        }
+    }
+    if let Err(reason) = genome.check(&genes) {
+        println!("Conflict: {reason}");
+    }
+}
+
+/// Parse a conf string into genes
+fn parse(conf: &str, schema: Schema) {
+    let conf = match conf.parse::<conf::MallocConf>() {
+        Ok(conf) => conf,
+        Err(err) => {
+            println!("Invalid conf: {err}");
+            return;
+        }
+    };
+
+    let (genes, notes) = schema.encode(&conf);
+    println!(
+        "Genes {}",
+        genes
+            .iter()
+            .map(ToString::to_string)
+            .collect::<Vec<_>>()
+            .join(",")
+    );
+    let decoded = schema
+        .decode(&genes)
+        .expect("encoded genes should be in range");
+    println!("Conf {decoded}");
+    for note in notes {
+        println!("Note: {note}");
+    }
+    if let Err(reason) = schema.check(&decoded) {
+        println!("Conflict: {reason}");
+    }
 }
 
 /// Run the GA evolution to get the best options for jemalloc that
 /// result in the lowest memory usage.
-fn evolution(seconds: u64, payloads: bool, config: Option<String>) {
-    let genotype = ListGenotype::builder()
-        .with_genes_size(7)
-        .with_allele_list((0..20).collect())
+fn evolution(
+    measure: Measure,
+    mut experiment: Experiment,
+    genome: Genome,
+    state: PathBuf,
+    resume: Option<Checkpoint>,
+    cache: Cache,
+) {
+    let mut genotype = MultiListGenotype::builder()
+        .with_allele_lists(genome.allele_lists())
         .build()
         .unwrap();
 
+    let cache = Arc::new(Mutex::new(cache));
+    if let Some(resume) = &resume {
+        if let Err(err) = resume.check(&genome) {
+            panic!("the state file was written with a different schema or layout, {err}");
+        }
+
+        println!("Resuming from generation {}", resume.generation);
+        genotype.set_seed_genes_list(resume.population.clone());
+        cache.lock().unwrap().merge(&resume.cache);
+    }
+
+    measure.prepare(&mut experiment);
+    measure.schedule(&experiment);
+    let failures = Arc::new(Mutex::new(Failures::default()));
     let mut evolve = Evolve::builder()
         .with_genotype(genotype)
         .with_target_population_size(20)
         .with_max_stale_generations(50)
         .with_fitness(MallocFitness {
-            seconds,
-            payloads,
-            config,
+            experiment,
+            measure,
+            genome: genome.clone(),
+            cache: Arc::clone(&cache),
+            failures: Arc::clone(&failures),
         })
         .with_par_fitness(true)
         .with_fitness_ordering(FitnessOrdering::Minimize)
@@ -122,51 +468,83 @@ fn evolution(seconds: u64, payloads: bool, config: Option<String>) {
         .with_mutate(MutateSingleGene::new(0.2))
         .with_crossover(CrossoverClone::new())
         .with_select(SelectElite::new(0.9))
-        .with_reporter(EvolveReporterSimple::new_with_flags(
-            10, true, true, true, true, true,
+        .with_extension(Resume::new(resume.as_ref()))
+        .with_reporter(CheckpointReporter::new(
+            EvolveReporterSimple::new_with_flags(10, true, true, true, true, true),
+            state,
+            Arc::clone(&cache),
+            resume.is_some(),
         ))
         .build()
         .unwrap();
 
+    if let Some(resume) = &resume {
+        resume.restore(&mut evolve.state, &mut evolve.genotype);
+    }
+
     evolve.call();
 
     if let Some((best_genes, fitness_score)) = evolve.best_genes_and_fitness_score() {
         println!("Best genes {:?}", best_genes);
-        println!(
-            "Best conf {}",
-            agent::MallocConf::from(best_genes.as_ref()).to_string()
-        );
+        let confs = genome
+            .confs(&best_genes)
+            .expect("the best genes should come from the genotype");
+        for (process, conf) in confs {
+            match process {
+                Some(process) => println!("Best conf for {process}: {conf}"),
+                None => println!("Best conf {conf}"),
+            }
+        }
         println!("Best score {:?}", fitness_score);
     } else {
         println!("Duff run");
     }
+    println!("Failures: {}", failures.lock().unwrap());
 }
 
 #[derive(Clone, Debug)]
 struct MallocFitness {
-    seconds: u64,
-    payloads: bool,
-    config: Option<String>,
+    measure: Measure,
+    experiment: Experiment,
+    genome: Genome,
+    cache: SharedCache,
+    failures: SharedFailures,
 }
 
 impl Fitness for MallocFitness {
-    type Genotype = ListGenotype<usize>;
+    type Genotype = MultiListGenotype<usize>;
     fn calculate_for_chromosome(
         &mut self,
         chromosome: &FitnessChromosome<Self>,
         _genotype: &Self::Genotype,
     ) -> Option<FitnessValue> {
-        let var_name = agent::MallocConf::from(chromosome.genes.as_ref());
-        let conf = var_name;
-        let rss = tokio::runtime::Runtime::new()
-            .unwrap()
-            .block_on(agent::run_container(
-                conf,
-                self.seconds,
-                self.payloads,
-                self.config.as_ref().map(|s| s.as_str()),
-            ));
-
-        rss.map(|r| r.total() as isize)
+        let conf = self
+            .genome
+            .decode(&chromosome.genes)
+            .expect("genes should come from the genotype");
+        if let Err(reason) = self.genome.check(&chromosome.genes) {
+            println!("Skipping {conf}: {reason}");
+            return None;
+        }
+
+        let (samples, _) =
+            match self
+                .measure
+                .samples(&conf, &self.experiment, &self.cache, false, &self.failures)
+            {
+                Ok(samples) => samples,
+                // Crashing confs get the worst fitness, so they're bred out.
+                Err(err @ RunError::Crashed { .. }) => {
+                    println!("Penalising {conf}: {err}");
+                    return Some(FitnessValue::MAX);
+                }
+                Err(err) => {
+                    println!("Penalising {conf}: {err}");
+                    return None;
+                }
+            };
+        self.measure
+            .score(&samples)
+            .map(|score| score.round() as FitnessValue)
     }
 }
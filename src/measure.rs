@@ -0,0 +1,320 @@
+use clap::Args;
+use std::{path::PathBuf, time::Duration};
+
+use crate::{
+    cache::{self, CacheKey, SharedCache},
+    conf::RunConf,
+    docker,
+    error::{Failure, RunError, SharedFailures},
+    experiment::{ContainerArgs, Experiment, TargetSpec},
+    load::Generator,
+    memory::{MemoryStats, Source},
+    scheduler,
+    stats::{Aggregate, Metric, Summary},
+    target,
+};
+
+const RUN_FOR_SECONDS: u64 = 60;
+
+/// How each conf is measured, shared by `Evolve` and `Run`.
+#[derive(Args, Debug, Clone)]
+pub struct Measure {
+    /// The experiment file describing the processes to measure. Defaults to
+    /// the built in agent experiment
+    #[arg(short, long)]
+    pub experiment: Option<String>,
+
+    /// Time in seconds to run for
+    #[arg(short, long, default_value_t = RUN_FOR_SECONDS)]
+    pub seconds: u64,
+
+    /// Send payloads via dogstatsd, and the experiment's generators, while
+    /// running
+    #[arg(short, long)]
+    pub payloads: bool,
+
+    /// The config file to use
+    #[arg(short, long)]
+    pub config: Option<String>,
+
+    /// Number of times to run each conf
+    #[arg(short, long, default_value_t = 1)]
+    pub trials: usize,
+
+    /// How the samples from the trials are combined into one score
+    #[arg(long, value_enum, default_value_t = Aggregate::Median)]
+    pub aggregate: Aggregate,
+
+    /// The k in mean + k * stddev for the mean-stddev aggregate
+    #[arg(long, default_value_t = 1.0)]
+    pub stddevs: f64,
+
+    /// Which memory figure is measured
+    #[arg(long, value_enum, default_value_t = Source::Rss)]
+    pub source: Source,
+
+    /// Which reading of the memory time series each trial is scored by
+    #[arg(long, value_enum, default_value_t = Metric::Final)]
+    pub metric: Metric,
+
+    /// Seconds to ignore at the start of the run for the steady metric
+    #[arg(long, default_value_t = 0)]
+    pub warmup: u64,
+
+    /// Times to run failed trials again when the failure wasn't the conf's
+    /// fault, such as docker hiccups
+    #[arg(long, default_value_t = 2)]
+    pub retries: usize,
+
+    /// Seconds between memory samples during the run. 0 only samples at
+    /// the end
+    #[arg(long, default_value_t = 5)]
+    pub interval: u64,
+
+    /// The most runs at once. Defaults to as many as the host's cores and
+    /// memory fit for docker targets, and one for local ones. Runs that
+    /// share a socket, log file or port on the host are always one at a time
+    #[arg(long)]
+    pub parallel: Option<usize>,
+
+    /// Also print jemalloc's stats every this many bytes allocated, for
+    /// binaries such as Go ones that exit without printing them. The prints
+    /// allocate, so the memory measured changes. Needs jemalloc 5.3
+    #[arg(long)]
+    pub stats_interval: Option<u64>,
+
+    /// Pin each concurrent run to its own cores
+    #[arg(long)]
+    pub pin: bool,
+
+    /// Directory every run's log is archived in, under the experiment's
+    /// hash and named by the conf's hash
+    #[arg(long, default_value = "jemopt-runs")]
+    pub output: PathBuf,
+
+    #[command(flatten)]
+    pub container: ContainerArgs,
+}
+
+impl Measure {
+    /// Load the experiment with the container overrides applied. Overrides
+    /// are folded into the experiment hash so their samples are cached apart.
+    pub fn experiment(&self) -> Experiment {
+        let mut experiment = Experiment::load(self.experiment.as_deref());
+        let changed = match &mut experiment.target {
+            TargetSpec::Docker(container) => self.container.apply(container),
+            _ if self.container.is_empty() => false,
+            _ => panic!("container overrides need a docker target"),
+        };
+        if self.source.is_cgroup() && !experiment.target.has_cgroup() {
+            panic!(
+                "the cgroup sources need a docker target, a process target with a cgroup or a command with its own snapshot"
+            );
+        }
+        if changed {
+            experiment.hash = cache::stable_hash(
+                format!("{:016x}{:?}", experiment.hash, experiment.target).as_bytes(),
+            );
+        }
+        experiment
+    }
+
+    /// Get the target ready to run before any runs start. Docker images are
+    /// pulled, pinned to their digest and checked for the preloads.
+    pub fn prepare(&self, experiment: &mut Experiment) {
+        if let Some(bytes) = self.stats_interval {
+            target::set_stats_interval(bytes);
+        }
+        if let TargetSpec::Docker(container) = &mut experiment.target {
+            let runtime = tokio::runtime::Runtime::new().unwrap();
+            if let Err(err) = runtime.block_on(docker::prepare(container)) {
+                panic!("{err}");
+            }
+        }
+    }
+
+    /// Size the run slots for the experiment's resources. Local targets run
+    /// one at a time unless `--parallel` says otherwise, and runs that would
+    /// share something on the host can't run at once at all.
+    pub fn schedule(&self, experiment: &Experiment) {
+        let (cpus, memory) = experiment.target.resources();
+        let local = !matches!(experiment.target, TargetSpec::Docker(_));
+        let parallel = match self.shared(experiment) {
+            Some(shared) if self.parallel.is_some_and(|parallel| parallel > 1) => {
+                panic!("concurrent runs would share {shared}, so --parallel must be 1")
+            }
+            Some(_) => Some(1),
+            None if local => Some(self.parallel.unwrap_or(1)),
+            None => self.parallel,
+        };
+        scheduler::init(parallel, cpus, memory, self.pin);
+    }
+
+    /// What concurrent runs would share on the host, if anything: the paths
+    /// load goes through, and the ports of local targets, which listen on
+    /// the experiment's ports themselves.
+    fn shared(&self, experiment: &Experiment) -> Option<String> {
+        if !self.payloads {
+            return None;
+        }
+        let socket = experiment
+            .load
+            .socket
+            .as_ref()
+            .map(|socket| format!("the dogstatsd socket {}", socket.path().display()));
+        let files = experiment
+            .generators
+            .iter()
+            .filter_map(|generator| match generator {
+                Generator::LogsFile(logs) => Some(format!("the log file {}", logs.path.display())),
+                _ => None,
+            });
+        let mut paths = socket.into_iter().chain(files);
+        if matches!(experiment.target, TargetSpec::Docker(_)) {
+            return paths.next();
+        }
+        paths.next().or_else(|| {
+            experiment
+                .target
+                .dogstatsd_port()
+                .map(|port| format!("{port}/udp"))
+                .into_iter()
+                .chain(experiment.generators.iter().filter_map(Generator::port))
+                .next()
+                .map(|port| format!("port {port}"))
+        })
+    }
+
+    pub fn cache_key(&self, conf: &RunConf, experiment: &Experiment) -> CacheKey {
+        CacheKey {
+            conf: conf.to_string(),
+            image: experiment.target.name(),
+            config_hash: cache::config_hash(self.config.as_deref()),
+            experiment_hash: experiment.hash,
+            seconds: self.seconds,
+            payloads: self.payloads,
+            source: self.source,
+            metric: self.metric,
+            warmup: self.warmup,
+            interval: self.interval,
+            stats_interval: self.stats_interval,
+        }
+    }
+
+    /// Collect at least `trials` samples of the total memory for the conf,
+    /// topping up the cached samples by running more containers. With
+    /// `fresh` every trial is run regardless of the cache. Trials that fail
+    /// in a way worth retrying are run again, up to `retries` times. Returns
+    /// all the samples, along with the full stats of any trials that were
+    /// run, or the last error if there are no samples at all. A crash in any
+    /// trial is returned as the error.
+    pub fn samples(
+        &self,
+        conf: &RunConf,
+        experiment: &Experiment,
+        cache: &SharedCache,
+        fresh: bool,
+        failures: &SharedFailures,
+    ) -> Result<(Vec<usize>, Option<MemoryStats>), RunError> {
+        let key = self.cache_key(conf, experiment);
+        let mut samples = if fresh {
+            Vec::new()
+        } else {
+            cache
+                .lock()
+                .unwrap()
+                .samples(&key)
+                .unwrap_or_default()
+                .iter()
+                // Older caches may hold totals of 0 from failed measurements.
+                .filter(|total| **total > 0)
+                .copied()
+                .collect()
+        };
+
+        // Cached samples need no run slot.
+        if samples.len() >= self.trials {
+            return Ok((samples, None));
+        }
+        let lease = scheduler::acquire();
+        let mut experiment = experiment.clone();
+        if let Some(cpuset) = lease.cpuset() {
+            experiment.target.pin(cpuset);
+        }
+        let experiment = &experiment;
+
+        let runtime = tokio::runtime::Runtime::new().unwrap();
+        let mut all_stats: Option<MemoryStats> = None;
+        let mut last_error = None;
+
+        for attempt in 0..=self.retries {
+            if samples.len() >= self.trials {
+                break;
+            }
+
+            let (stats, mut errors) = runtime.block_on(target::run_trials(
+                experiment,
+                conf,
+                self.trials - samples.len(),
+                self.seconds,
+                self.payloads,
+                self.config.as_deref(),
+                Duration::from_secs(self.interval),
+                &self.output,
+            ));
+
+            if let Some(stats) = stats {
+                let mut cache = cache.lock().unwrap();
+                for total in stats.totals(
+                    self.source,
+                    &experiment.processes,
+                    self.metric,
+                    self.warmup as f64,
+                ) {
+                    // Nothing was measured, such as when no process matched.
+                    if total == 0 {
+                        errors.push(RunError::MeasurementFailed(
+                            "the total memory was 0".to_string(),
+                        ));
+                        continue;
+                    }
+                    cache.record(&key, total);
+                    samples.push(total);
+                }
+                match &mut all_stats {
+                    Some(all_stats) => all_stats.extend(&stats),
+                    None => all_stats = Some(stats),
+                }
+            }
+
+            let retry =
+                attempt < self.retries && errors.iter().any(|err| err.failure() == Failure::Retry);
+            let mut failures = failures.lock().unwrap();
+            for err in errors {
+                failures.record(&err, retry && err.failure() == Failure::Retry);
+                // A crash is penalised whatever the other trials managed.
+                if matches!(err, RunError::Crashed { .. }) {
+                    failures.penalise();
+                    return Err(err);
+                }
+                last_error = Some(err);
+            }
+            if !retry {
+                break;
+            }
+        }
+
+        match last_error {
+            Some(err) if samples.is_empty() => {
+                failures.lock().unwrap().penalise();
+                Err(err)
+            }
+            _ => Ok((samples, all_stats)),
+        }
+    }
+
+    /// Combine the samples into a single score with the chosen aggregate.
+    pub fn score(&self, samples: &[usize]) -> Option<f64> {
+        Summary::new(samples).map(|summary| summary.aggregate(self.aggregate, self.stddevs))
+    }
+}
@@ -0,0 +1,385 @@
+use clap::ValueEnum;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use crate::{
+    error::RunError,
+    experiment::ProcessSpec,
+    stats::{self, Metric},
+};
+
+/// Run inside the container to dump the pid and `smaps_rollup` of every process,
+/// followed by the container's cgroup memory usage. Falls back to the cgroup
+/// v1 files when v2 isn't mounted.
+pub const SNAPSHOT_SCRIPT: &str = r#"
+for p in /proc/[0-9]*; do
+  [ -r "$p/smaps_rollup" ] || continue
+  echo "== pid ${p#/proc/}"
+  printf '== process '
+  tr '\0' ' ' < "$p/cmdline"
+  echo
+  cat "$p/smaps_rollup"
+done
+echo '== cgroup'
+cat /sys/fs/cgroup/memory.current 2>/dev/null || cat /sys/fs/cgroup/memory/memory.usage_in_bytes
+cat /sys/fs/cgroup/memory.stat 2>/dev/null || cat /sys/fs/cgroup/memory/memory.stat
+"#;
+
+/// Which memory figure is measured.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Source {
+    /// Resident set, including the shared pages of the preloaded libraries.
+    Rss,
+    /// Proportional set, which splits shared pages between their users.
+    Pss,
+    PrivateDirty,
+    Anonymous,
+    AnonHugePages,
+    /// The container cgroup's `memory.current`.
+    CgroupCurrent,
+    /// The `anon` line of the container cgroup's `memory.stat`.
+    CgroupAnon,
+}
+
+impl Source {
+    /// Whether it's read from the cgroup rather than the processes.
+    pub fn is_cgroup(self) -> bool {
+        matches!(self, Source::CgroupCurrent | Source::CgroupAnon)
+    }
+}
+
+/// The `smaps_rollup` figures for a process, in kB.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ProcessMemory {
+    pub rss: usize,
+    pub pss: usize,
+    pub private_dirty: usize,
+    pub anonymous: usize,
+    pub anon_huge_pages: usize,
+}
+
+impl ProcessMemory {
+    fn add(&mut self, other: &ProcessMemory) {
+        self.rss += other.rss;
+        self.pss += other.pss;
+        self.private_dirty += other.private_dirty;
+        self.anonymous += other.anonymous;
+        self.anon_huge_pages += other.anon_huge_pages;
+    }
+
+    fn get(&self, source: Source) -> usize {
+        match source {
+            Source::Rss => self.rss,
+            Source::Pss => self.pss,
+            Source::PrivateDirty => self.private_dirty,
+            Source::Anonymous => self.anonymous,
+            Source::AnonHugePages => self.anon_huge_pages,
+            Source::CgroupCurrent | Source::CgroupAnon => 0,
+        }
+    }
+}
+
+/// The container's cgroup memory usage, in kB.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct CgroupMemory {
+    pub current: usize,
+    pub anon: usize,
+}
+
+/// Memory of each tracked process, and the container as a whole, at a
+/// point during the run.
+#[derive(Debug, Clone)]
+pub struct Snapshot {
+    /// Seconds since the container started.
+    pub elapsed: f64,
+    /// Keyed by the process name from the experiment.
+    pub processes: BTreeMap<String, ProcessMemory>,
+    /// The pids of each tracked process, when the snapshot has them.
+    pub pids: BTreeMap<String, BTreeSet<u32>>,
+    pub cgroup: CgroupMemory,
+}
+
+impl Snapshot {
+    /// Parse the output of [`SNAPSHOT_SCRIPT`]. Fails if any required
+    /// process is missing.
+    pub fn parse(output: &str, elapsed: f64, specs: &[ProcessSpec]) -> Result<Self, RunError> {
+        let mut processes = BTreeMap::new();
+        let mut pids: BTreeMap<String, BTreeSet<u32>> = BTreeMap::new();
+        let mut pid = None;
+
+        let mut current: Option<ProcessMemory> = None;
+        let mut matched: Option<&ProcessSpec> = None;
+        let mut cgroup_lines = Vec::new();
+        let mut in_cgroup = false;
+
+        let mut finish = |current: &mut Option<ProcessMemory>, matched: Option<&ProcessSpec>| {
+            if let (Some(memory), Some(spec)) = (current.take(), matched) {
+                // Forked workers with the same command line are counted
+                // against the same process.
+                processes
+                    .entry(spec.name.clone())
+                    .or_insert_with(ProcessMemory::default)
+                    .add(&memory);
+            }
+        };
+
+        for line in output.lines() {
+            if let Some(number) = line.strip_prefix("== pid ") {
+                pid = number.trim().parse().ok();
+            } else if let Some(cmdline) = line.strip_prefix("== process ") {
+                finish(&mut current, matched);
+                current = Some(ProcessMemory::default());
+                matched = specs
+                    .iter()
+                    .find(|spec| spec.matcher.is_match(cmdline.trim()));
+                if let (Some(spec), Some(pid)) = (matched, pid.take()) {
+                    pids.entry(spec.name.clone()).or_default().insert(pid);
+                }
+            } else if line == "== cgroup" {
+                finish(&mut current, matched);
+                in_cgroup = true;
+            } else if in_cgroup {
+                cgroup_lines.push(line);
+            } else if let Some(memory) = current.as_mut() {
+                let mut fields = line.split_whitespace();
+                let (Some(key), Some(value)) = (fields.next(), fields.next()) else {
+                    continue;
+                };
+                let Ok(value) = value.parse::<usize>() else {
+                    continue;
+                };
+                match key {
+                    "Rss:" => memory.rss = value,
+                    "Pss:" => memory.pss = value,
+                    "Private_Dirty:" => memory.private_dirty = value,
+                    "Anonymous:" => memory.anonymous = value,
+                    "AnonHugePages:" => memory.anon_huge_pages = value,
+                    _ => {}
+                }
+            }
+        }
+        finish(&mut current, matched);
+
+        if let Some(missing) = specs
+            .iter()
+            .find(|spec| spec.required && !processes.contains_key(&spec.name))
+        {
+            return Err(RunError::ProcessMissing(missing.name.clone()));
+        }
+
+        Ok(Snapshot {
+            elapsed,
+            processes,
+            pids,
+            cgroup: parse_cgroup(&cgroup_lines),
+        })
+    }
+
+    /// A process that has been replaced since the previous snapshot, with
+    /// none of its earlier pids still running.
+    pub fn restarted<'a>(&'a self, previous: &Snapshot) -> Option<&'a str> {
+        self.pids
+            .iter()
+            .find(|(name, pids)| {
+                previous
+                    .pids
+                    .get(*name)
+                    .is_some_and(|before| before.is_disjoint(pids))
+            })
+            .map(|(name, _)| name.as_str())
+    }
+
+    /// The weighted sum of the processes' memory, or the cgroup's usage.
+    pub fn total(&self, source: Source, specs: &[ProcessSpec]) -> f64 {
+        match source {
+            Source::CgroupCurrent => self.cgroup.current as f64,
+            Source::CgroupAnon => self.cgroup.anon as f64,
+            _ => specs
+                .iter()
+                .filter_map(|spec| {
+                    self.processes
+                        .get(&spec.name)
+                        .map(|memory| memory.get(source) as f64 * spec.weight)
+                })
+                .sum(),
+        }
+    }
+}
+
+/// The first line is the usage in bytes, the rest are `memory.stat`.
+fn parse_cgroup(lines: &[&str]) -> CgroupMemory {
+    let current = lines
+        .first()
+        .and_then(|line| line.trim().parse::<usize>().ok())
+        .unwrap_or_default();
+    let stat = lines
+        .iter()
+        .skip(1)
+        .filter_map(|line| {
+            let (key, value) = line.split_once(' ')?;
+            Some((key, value.trim().parse::<usize>().ok()?))
+        })
+        .collect::<HashMap<_, _>>();
+
+    CgroupMemory {
+        current: current / 1024,
+        // cgroup v1 calls it rss.
+        anon: stat
+            .get("anon")
+            .or(stat.get("rss"))
+            .copied()
+            .unwrap_or_default()
+            / 1024,
+    }
+}
+
+/// The memory time series of each trial.
+#[derive(Debug, Clone)]
+pub struct MemoryStats {
+    series: Vec<Vec<Snapshot>>,
+}
+
+impl MemoryStats {
+    pub fn new(series: Vec<Snapshot>) -> Self {
+        Self {
+            series: vec![series],
+        }
+    }
+
+    /// Add the samples from another set of trials.
+    pub fn extend(&mut self, other: &MemoryStats) {
+        self.series.extend(other.series.iter().cloned());
+    }
+
+    pub fn series(&self) -> &[Vec<Snapshot>] {
+        &self.series
+    }
+
+    /// The total memory of each trial, reduced from its time series with
+    /// the metric.
+    pub fn totals(
+        &self,
+        source: Source,
+        specs: &[ProcessSpec],
+        metric: Metric,
+        warmup: f64,
+    ) -> Vec<usize> {
+        self.series
+            .iter()
+            .map(|series| {
+                let points = series
+                    .iter()
+                    .map(|snapshot| (snapshot.elapsed, snapshot.total(source, specs)))
+                    .collect::<Vec<_>>();
+                stats::reduce(metric, &points, warmup).round() as usize
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use regex::Regex;
+
+    fn spec(name: &str, matcher: &str, required: bool, weight: f64) -> ProcessSpec {
+        ProcessSpec {
+            name: name.to_string(),
+            matcher: Regex::new(matcher).unwrap(),
+            required,
+            weight,
+            binary: None,
+        }
+    }
+
+    fn specs() -> Vec<ProcessSpec> {
+        vec![
+            spec("agent", r"^\S*agent run\b", true, 1.0),
+            spec("trace-agent", r"^\S*trace-agent\b", false, 2.0),
+        ]
+    }
+
+    const OUTPUT: &str = "\
+== pid 10
+== process /opt/agent run -c /etc
+Rss:                1000 kB
+Pss:                 800 kB
+Private_Dirty:       600 kB
+Anonymous:           500 kB
+AnonHugePages:         0 kB
+== pid 11
+== process /opt/agent run -c /etc
+Rss:                 200 kB
+Pss:                 100 kB
+== pid 12
+== process /bin/sh -c sleep
+Rss:                9999 kB
+== pid 13
+== process /opt/trace-agent
+Rss:                 300 kB
+== cgroup
+4194304
+anon 2097152
+file 1048576
+";
+
+    #[test]
+    fn parses_processes_and_cgroup() {
+        let snapshot = Snapshot::parse(OUTPUT, 5.0, &specs()).unwrap();
+        let agent = snapshot.processes["agent"];
+        assert_eq!(
+            (agent.rss, agent.pss, agent.private_dirty),
+            (1200, 900, 600)
+        );
+        assert_eq!(snapshot.processes["trace-agent"].rss, 300);
+        assert_eq!(snapshot.processes.len(), 2);
+        assert_eq!(snapshot.pids["agent"], BTreeSet::from([10, 11]));
+        assert_eq!(
+            (snapshot.cgroup.current, snapshot.cgroup.anon),
+            (4096, 2048)
+        );
+    }
+
+    #[test]
+    fn weights_the_total() {
+        let snapshot = Snapshot::parse(OUTPUT, 5.0, &specs()).unwrap();
+        assert_eq!(snapshot.total(Source::Rss, &specs()), 1200.0 + 2.0 * 300.0);
+        assert_eq!(snapshot.total(Source::CgroupAnon, &specs()), 2048.0);
+    }
+
+    #[test]
+    fn reads_cgroup_v1_rss() {
+        let output = "== cgroup\n1024\nrss 4096\n";
+        let snapshot = Snapshot::parse(output, 0.0, &[]).unwrap();
+        assert_eq!((snapshot.cgroup.current, snapshot.cgroup.anon), (1, 4));
+    }
+
+    #[test]
+    fn fails_without_a_required_process() {
+        let specs = [spec("missing", "^nothing", true, 1.0)];
+        assert!(matches!(
+            Snapshot::parse(OUTPUT, 0.0, &specs),
+            Err(RunError::ProcessMissing(name)) if name == "missing"
+        ));
+    }
+
+    #[test]
+    fn skips_optional_processes() {
+        let specs = [spec("missing", "^nothing", false, 1.0)];
+        let snapshot = Snapshot::parse(OUTPUT, 0.0, &specs).unwrap();
+        assert!(snapshot.processes.is_empty());
+        assert_eq!(snapshot.total(Source::Rss, &specs), 0.0);
+    }
+
+    #[test]
+    fn detects_restarts() {
+        let before = Snapshot::parse(OUTPUT, 0.0, &specs()).unwrap();
+        let same = Snapshot::parse(&OUTPUT.replace("== pid 10", "== pid 20"), 5.0, &specs());
+        assert_eq!(same.unwrap().restarted(&before), None);
+
+        let replaced = OUTPUT
+            .replace("== pid 10", "== pid 20")
+            .replace("== pid 11", "== pid 21");
+        let after = Snapshot::parse(&replaced, 5.0, &specs()).unwrap();
+        assert_eq!(after.restarted(&before), Some("agent"));
+    }
+}
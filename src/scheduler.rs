@@ -0,0 +1,245 @@
+use std::{
+    fs,
+    sync::{Condvar, Mutex, OnceLock},
+    thread,
+};
+
+/// Slots for concurrent runs, each with its own cores when pinning. The
+/// fitness threads block until a slot is free.
+struct Scheduler {
+    /// The cpuset of each free slot, `None` when not pinning.
+    free: Mutex<Vec<Option<String>>>,
+    returned: Condvar,
+}
+
+static SCHEDULER: OnceLock<Scheduler> = OnceLock::new();
+
+/// A slot, given back when dropped.
+pub struct Lease {
+    cpuset: Option<String>,
+}
+
+impl Lease {
+    /// The cores the run is pinned to.
+    pub fn cpuset(&self) -> Option<&str> {
+        self.cpuset.as_deref()
+    }
+}
+
+impl Drop for Lease {
+    fn drop(&mut self) {
+        let scheduler = SCHEDULER.get().expect("scheduler should be initialised");
+        scheduler.free.lock().unwrap().push(self.cpuset.take());
+        scheduler.returned.notify_one();
+    }
+}
+
+/// The cores this process may run on, from its affinity.
+fn host_cores() -> Vec<usize> {
+    let allowed = fs::read_to_string("/proc/self/status")
+        .ok()
+        .and_then(|status| {
+            status
+                .lines()
+                .find_map(|line| line.strip_prefix("Cpus_allowed_list:"))
+                .map(|list| list.trim().to_string())
+        })
+        .unwrap_or_default();
+
+    let cores = parse_cores(&allowed);
+    if cores.is_empty() {
+        (0..thread::available_parallelism().map_or(1, |n| n.get())).collect()
+    } else {
+        cores
+    }
+}
+
+/// The cores in a list like `0-3,8,10-11`, skipping what doesn't parse.
+fn parse_cores(list: &str) -> Vec<usize> {
+    list.split(',')
+        .filter_map(|range| match range.split_once('-') {
+            Some((first, last)) => Some(first.parse().ok()?..=last.parse().ok()?),
+            None => range.parse().ok().map(|core| core..=core),
+        })
+        .flatten()
+        .collect()
+}
+
+/// The host's available memory in MiB.
+fn host_memory() -> Option<u64> {
+    fs::read_to_string("/proc/meminfo")
+        .ok()?
+        .lines()
+        .find_map(|line| line.strip_prefix("MemAvailable:"))?
+        .trim()
+        .trim_end_matches("kB")
+        .trim()
+        .parse::<u64>()
+        .ok()
+        .map(|kb| kb / 1024)
+}
+
+/// Size the slots from the host's cores and memory, and what each run
+/// needs. Without `parallel` as many runs as fit are allowed at once. With
+/// `pin` each slot gets `cpus` cores of its own, rounded up.
+pub fn init(parallel: Option<usize>, cpus: Option<f64>, memory: Option<u64>, pin: bool) {
+    let cores = host_cores();
+    let free = slots(&cores, host_memory(), parallel, cpus, memory, pin);
+    println!(
+        "Running up to {} at once on {} cores{}",
+        free.len(),
+        cores.len(),
+        if pin { ", pinned" } else { "" }
+    );
+
+    if SCHEDULER
+        .set(Scheduler {
+            free: Mutex::new(free),
+            returned: Condvar::new(),
+        })
+        .is_err()
+    {
+        panic!("scheduler initialised twice");
+    }
+}
+
+/// The cpuset of each slot, `None` when not pinning, sized as [`init`]
+/// describes from the host's cores and available memory in MiB.
+fn slots(
+    cores: &[usize],
+    host_memory: Option<u64>,
+    parallel: Option<usize>,
+    cpus: Option<f64>,
+    memory: Option<u64>,
+    pin: bool,
+) -> Vec<Option<String>> {
+    let cores_per_run = cpus.map_or(1, |cpus| cpus.ceil().max(1.0) as usize);
+    if pin && cores.len() < cores_per_run {
+        panic!(
+            "--pin needs {cores_per_run} cores for each run, but only {} are available",
+            cores.len()
+        );
+    }
+
+    let fit_cores = (cores.len() / cores_per_run).max(1);
+    let fit_memory = match (memory, host_memory) {
+        (Some(memory), Some(host)) => (host / memory.max(1)).max(1) as usize,
+        _ => usize::MAX,
+    };
+    let fit = fit_cores.min(fit_memory);
+
+    let parallel = match parallel {
+        Some(parallel) if pin && parallel > fit_cores => {
+            println!(
+                "Only {fit_cores} runs of {cores_per_run} cores can be pinned, not {parallel}"
+            );
+            fit_cores
+        }
+        Some(parallel) => {
+            if parallel > fit {
+                println!("{parallel} runs at once is more than the {fit} the host has room for");
+            }
+            parallel.max(1)
+        }
+        None => fit,
+    };
+
+    (0..parallel)
+        .map(|slot| {
+            pin.then(|| {
+                cores[slot * cores_per_run..(slot + 1) * cores_per_run]
+                    .iter()
+                    .map(ToString::to_string)
+                    .collect::<Vec<_>>()
+                    .join(",")
+            })
+        })
+        .collect()
+}
+
+/// Wait for a free slot.
+pub fn acquire() -> Lease {
+    let scheduler = SCHEDULER.get().expect("scheduler should be initialised");
+    let mut free = scheduler.free.lock().unwrap();
+    loop {
+        if let Some(cpuset) = free.pop() {
+            return Lease { cpuset };
+        }
+        free = scheduler.returned.wait(free).unwrap();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cpusets(slots: Vec<Option<String>>) -> Vec<String> {
+        slots.into_iter().map(Option::unwrap).collect()
+    }
+
+    #[test]
+    fn parses_core_lists() {
+        assert_eq!(parse_cores("0-3,8,10-11"), vec![0, 1, 2, 3, 8, 10, 11]);
+        assert_eq!(parse_cores("5"), vec![5]);
+        assert_eq!(parse_cores(""), Vec::<usize>::new());
+        assert_eq!(parse_cores("x,2"), vec![2]);
+    }
+
+    #[test]
+    fn fits_as_many_runs_as_cores_and_memory_allow() {
+        let cores = (0..8).collect::<Vec<_>>();
+        assert_eq!(slots(&cores, None, None, None, None, false).len(), 8);
+        assert_eq!(slots(&cores, None, None, Some(1.5), None, false).len(), 4);
+        assert_eq!(
+            slots(&cores, Some(10_000), None, None, Some(4000), false).len(),
+            2
+        );
+        // Memory for less than one run still allows one.
+        assert_eq!(
+            slots(&cores, Some(1000), None, None, Some(4000), false).len(),
+            1
+        );
+        assert_eq!(slots(&cores, Some(1000), None, None, None, false).len(), 8);
+    }
+
+    #[test]
+    fn slices_the_cores_between_pinned_slots() {
+        let cores = vec![0, 1, 2, 3, 8, 9, 10];
+        assert_eq!(
+            cpusets(slots(&cores, None, None, Some(2.0), None, true)),
+            vec!["0,1", "2,3", "8,9"]
+        );
+        assert_eq!(
+            cpusets(slots(&cores, None, Some(2), Some(3.0), None, true)),
+            vec!["0,1,2", "3,8,9"]
+        );
+    }
+
+    #[test]
+    fn leaves_slots_unpinned_without_pin() {
+        let slots = slots(&[0, 1], None, Some(2), None, None, false);
+        assert_eq!(slots, vec![None, None]);
+    }
+
+    #[test]
+    fn runs_more_than_fit_when_asked_unless_pinned() {
+        let cores = [0, 1, 2];
+        assert_eq!(slots(&cores, None, Some(5), None, None, false).len(), 5);
+        assert_eq!(
+            cpusets(slots(&cores, None, Some(5), None, None, true)),
+            vec!["0", "1", "2"]
+        );
+        assert_eq!(slots(&cores, None, Some(0), None, None, false).len(), 1);
+    }
+
+    #[test]
+    fn runs_one_at_a_time_on_fewer_cores_than_a_run_needs() {
+        assert_eq!(slots(&[0], None, None, Some(4.0), None, false).len(), 1);
+    }
+
+    #[test]
+    #[should_panic(expected = "--pin needs 4 cores")]
+    fn cannot_pin_to_fewer_cores_than_a_run_needs() {
+        slots(&[0, 1], None, None, Some(4.0), None, true);
+    }
+}
@@ -0,0 +1,458 @@
+use serde::Deserialize;
+use std::{collections::BTreeMap, fs};
+
+use crate::conf::{self, MallocConf, Value};
+
+/// The search space used when no schema file is given.
+const DEFAULT_SCHEMA: &str = include_str!("../schema.toml");
+
+/// The jemalloc options the GA searches over. Each option is one gene.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Schema {
+    #[serde(rename = "option")]
+    pub options: Vec<OptionSpec>,
+
+    /// Combinations of values that must never be evaluated.
+    #[serde(default, rename = "conflict")]
+    pub conflicts: Vec<Conflict>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct OptionSpec {
+    pub name: String,
+    #[serde(flatten)]
+    pub kind: OptionKind,
+
+    /// Add an extra allele that leaves the option at the jemalloc default.
+    #[serde(default)]
+    pub optional: bool,
+
+    /// The option is only rendered when these other options are set to one
+    /// of the listed values.
+    #[serde(default)]
+    pub requires: Condition,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum OptionKind {
+    /// Every value from `min` to `max` inclusive, `step` apart.
+    Int {
+        min: i64,
+        max: i64,
+        #[serde(default = "default_step")]
+        step: i64,
+    },
+    /// Powers of two, with the exponent running from `min` to `max` inclusive.
+    Log2 {
+        min: u32,
+        max: u32,
+        #[serde(default = "default_step")]
+        step: i64,
+    },
+    /// One of the listed values.
+    Enum {
+        values: Vec<Value>,
+    },
+    Bool,
+}
+
+fn default_step() -> i64 {
+    1
+}
+
+/// Option name to the values that satisfy it. Satisfied when every named
+/// option is set to one of its values.
+pub type Condition = BTreeMap<String, Vec<Value>>;
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Conflict {
+    pub when: Condition,
+    #[serde(default)]
+    pub reason: Option<String>,
+}
+
+fn satisfied(condition: &Condition, conf: &MallocConf) -> bool {
+    condition.iter().all(|(name, values)| {
+        conf.get(name).is_some_and(|value| {
+            values
+                .iter()
+                .any(|allowed| allowed.to_string() == value.to_string())
+        })
+    })
+}
+
+/// The allele equal to the value, or else the closest integer.
+fn nearest(alleles: &[Option<Value>], wanted: &Value) -> Option<usize> {
+    let exact = alleles.iter().position(|allele| {
+        allele
+            .as_ref()
+            .is_some_and(|allele| allele.to_string() == wanted.to_string())
+    });
+    match wanted {
+        Value::Int(wanted) if exact.is_none() => alleles
+            .iter()
+            .enumerate()
+            .filter_map(|(idx, allele)| match allele {
+                Some(Value::Int(value)) => Some((idx, value.abs_diff(*wanted))),
+                _ => None,
+            })
+            .min_by_key(|(_, distance)| *distance)
+            .map(|(idx, _)| idx),
+        _ => exact,
+    }
+}
+
+impl OptionSpec {
+    /// All the values this option can take. The gene for this option is an
+    /// index into this list, `None` leaving the option unset.
+    pub fn alleles(&self) -> Vec<Option<Value>> {
+        let mut alleles: Vec<_> = match &self.kind {
+            OptionKind::Int { min, max, step } => (*min..=*max)
+                .step_by(*step as usize)
+                .map(Value::Int)
+                .collect(),
+            OptionKind::Log2 { min, max, step } => (*min..=*max)
+                .step_by(*step as usize)
+                .map(|exp| Value::Int(1 << exp))
+                .collect(),
+            OptionKind::Enum { values } => values.clone(),
+            OptionKind::Bool => vec![Value::Bool(false), Value::Bool(true)],
+        }
+        .into_iter()
+        .map(Some)
+        .collect();
+
+        if self.optional {
+            alleles.push(None);
+        }
+
+        alleles
+    }
+
+    fn validate(&self) -> Result<(), String> {
+        match &self.kind {
+            OptionKind::Int { min, max, step } if min > max || *step < 1 => Err(format!(
+                "{}: int range {min}..={max} step {step} is empty",
+                self.name
+            )),
+            OptionKind::Log2 { min, max, step } if min > max || *step < 1 || *max > 62 => {
+                Err(format!(
+                    "{}: log2 range {min}..={max} step {step} is invalid",
+                    self.name
+                ))
+            }
+            OptionKind::Enum { values } if values.is_empty() => {
+                Err(format!("{}: enum has no values", self.name))
+            }
+            _ => Ok(()),
+        }
+    }
+}
+
+impl Schema {
+    /// Load the schema from the given TOML file, or the built in default.
+    pub fn load(path: Option<&str>) -> Self {
+        let schema = match path {
+            Some(path) => {
+                let contents = fs::read_to_string(path).expect("schema file should be readable");
+                Self::parse(&contents)
+            }
+            None => Self::parse(DEFAULT_SCHEMA),
+        };
+
+        schema.unwrap_or_else(|err| panic!("invalid schema: {err}"))
+    }
+
+    pub fn parse(contents: &str) -> Result<Self, String> {
+        let schema: Schema = toml::from_str(contents).map_err(|err| err.to_string())?;
+        for option in &schema.options {
+            option.validate()?;
+        }
+
+        let conditions = schema
+            .options
+            .iter()
+            .map(|option| &option.requires)
+            .chain(schema.conflicts.iter().map(|conflict| &conflict.when));
+        for condition in conditions {
+            if let Some(name) = condition.keys().find(|name| schema.option(name).is_none()) {
+                return Err(format!("condition refers to unknown option {name}"));
+            }
+        }
+
+        Ok(schema)
+    }
+
+    pub fn option(&self, name: &str) -> Option<&OptionSpec> {
+        self.options.iter().find(|option| option.name == name)
+    }
+
+    /// The allele list for each gene, one per option.
+    pub fn allele_lists(&self) -> Vec<Vec<usize>> {
+        self.options
+            .iter()
+            .map(|option| (0..option.alleles().len()).collect())
+            .collect()
+    }
+
+    /// Turn a gene vector into the conf it represents. Options whose
+    /// requirements are not met are left out. Fails on genes that aren't
+    /// one per option or are out of range, as they can come from older
+    /// schemas.
+    pub fn decode(&self, genes: &[usize]) -> Result<MallocConf, String> {
+        self.decode_at(genes, 1)
+    }
+
+    /// [`Schema::decode`] for genes starting at `column` of a longer gene
+    /// vector, so errors name the column of the whole vector.
+    pub fn decode_at(&self, genes: &[usize], column: usize) -> Result<MallocConf, String> {
+        if genes.len() != self.options.len() {
+            return Err(format!(
+                "expected {} genes, one per schema option, got {}",
+                self.options.len(),
+                genes.len()
+            ));
+        }
+
+        let mut conf = MallocConf {
+            options: self
+                .options
+                .iter()
+                .zip(genes)
+                .enumerate()
+                .map(|(idx, (option, gene))| {
+                    let alleles = option.alleles();
+                    let value = alleles.get(*gene).ok_or_else(|| {
+                        format!(
+                            "gene {gene} in column {} is out of range for {}, which has {} values",
+                            column + idx,
+                            option.name,
+                            alleles.len()
+                        )
+                    })?;
+                    Ok(value.clone().map(|value| (option.name.clone(), value)))
+                })
+                .filter_map(Result::transpose)
+                .collect::<Result<_, String>>()?,
+        };
+
+        // Dropping an option can break the requirements of another, so keep
+        // going until nothing changes.
+        loop {
+            let before = conf.options.len();
+            let current = conf.clone();
+            conf.options.retain(|(name, _)| {
+                self.option(name)
+                    .is_some_and(|option| satisfied(&option.requires, &current))
+            });
+            if conf.options.len() == before {
+                return Ok(conf);
+            }
+        }
+    }
+
+    /// Find the gene vector that decodes closest to the conf. Alongside the
+    /// genes come notes on anything the schema couldn't represent exactly.
+    pub fn encode(&self, conf: &MallocConf) -> (Vec<usize>, Vec<String>) {
+        let mut notes = Vec::new();
+
+        let genes = self
+            .options
+            .iter()
+            .map(|option| {
+                let alleles = option.alleles();
+                let Some(wanted) = conf.get(&option.name) else {
+                    // Unset options are fine if the schema can leave them
+                    // unset, otherwise jemalloc's default is the closest.
+                    return alleles
+                        .iter()
+                        .position(Option::is_none)
+                        .or_else(|| {
+                            conf::default_value(&option.name)
+                                .and_then(|default| nearest(&alleles, &default))
+                        })
+                        .unwrap_or(0);
+                };
+
+                let nearest = nearest(&alleles, wanted).unwrap_or(0);
+                if alleles[nearest]
+                    .as_ref()
+                    .is_some_and(|allele| allele.to_string() == wanted.to_string())
+                {
+                    return nearest;
+                }
+
+                notes.push(format!(
+                    "{}:{wanted} is not in the schema, using {}",
+                    option.name,
+                    alleles[nearest]
+                        .as_ref()
+                        .map_or("the default".to_string(), ToString::to_string)
+                ));
+                nearest
+            })
+            .collect::<Vec<_>>();
+
+        let decoded = self
+            .decode(&genes)
+            .expect("encoded genes should be in range");
+        for (name, value) in &conf.options {
+            if self.option(name).is_none() {
+                notes.push(format!(
+                    "{name}:{value} is not searched by the schema, dropped"
+                ));
+            } else if decoded.get(name).is_none() {
+                notes.push(format!(
+                    "{name}:{value} is dropped because its requirements are not met"
+                ));
+            }
+        }
+        for (name, value) in &decoded.options {
+            if conf.get(name).is_none() {
+                notes.push(format!(
+                    "{name} is not set, the schema always sets it, using {value}"
+                ));
+            }
+        }
+
+        (genes, notes)
+    }
+
+    /// Check the conf doesn't hit any of the conflicts.
+    pub fn check(&self, conf: &MallocConf) -> Result<(), String> {
+        match self
+            .conflicts
+            .iter()
+            .find(|conflict| satisfied(&conflict.when, conf))
+        {
+            Some(conflict) => Err(conflict
+                .reason
+                .clone()
+                .unwrap_or_else(|| format!("conflicting options {:?}", conflict.when.keys()))),
+            None => Ok(()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn schema() -> Schema {
+        Schema::parse(
+            r#"
+            [[option]]
+            name = "tcache"
+            type = "bool"
+
+            [[option]]
+            name = "lg_tcache_max"
+            type = "int"
+            min = 10
+            max = 20
+            optional = true
+            requires = { tcache = [true] }
+
+            [[option]]
+            name = "dirty_decay_ms"
+            type = "enum"
+            values = [-1, 0, 1000, 10000]
+
+            [[option]]
+            name = "thp"
+            type = "enum"
+            values = ["default", "always", "never"]
+            "#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn round_trips_every_conf_in_the_schema() {
+        let schema = schema();
+        for genes in [[0, 11, 0, 0], [1, 0, 1, 2], [1, 10, 3, 1], [1, 5, 2, 0]] {
+            let conf = schema.decode(&genes).unwrap();
+            let (encoded, notes) = schema.encode(&conf);
+            assert_eq!(schema.decode(&encoded).unwrap(), conf, "{conf}");
+            assert!(notes.is_empty(), "{conf}: {notes:?}");
+        }
+    }
+
+    #[test]
+    fn encodes_exact_values() {
+        let schema = schema();
+        let conf = "tcache:true,lg_tcache_max:12,dirty_decay_ms:0,thp:always"
+            .parse()
+            .unwrap();
+        let (genes, notes) = schema.encode(&conf);
+        assert_eq!(genes, vec![1, 2, 1, 1]);
+        assert!(notes.is_empty(), "{notes:?}");
+    }
+
+    #[test]
+    fn encodes_the_nearest_value() {
+        let schema = schema();
+        let conf = "tcache:true,lg_tcache_max:22,dirty_decay_ms:900,thp:never"
+            .parse()
+            .unwrap();
+        let (genes, notes) = schema.encode(&conf);
+        assert_eq!(
+            schema.decode(&genes).unwrap().to_string(),
+            "tcache:true,lg_tcache_max:20,dirty_decay_ms:1000,thp:never"
+        );
+        assert_eq!(notes.len(), 2, "{notes:?}");
+    }
+
+    #[test]
+    fn encodes_unset_options_as_the_jemalloc_default() {
+        let schema = schema();
+        let (genes, _) = schema.encode(&MallocConf::default());
+        assert_eq!(
+            schema.decode(&genes).unwrap().to_string(),
+            "tcache:true,dirty_decay_ms:10000,thp:default"
+        );
+    }
+
+    #[test]
+    fn notes_options_outside_the_schema() {
+        let schema = schema();
+        let conf = "tcache:false,lg_tcache_max:12,prof:true".parse().unwrap();
+        let (genes, notes) = schema.encode(&conf);
+        assert_eq!(schema.decode(&genes).unwrap().get("lg_tcache_max"), None);
+        assert!(notes.iter().any(|note| note.starts_with("prof:true")));
+        assert!(notes
+            .iter()
+            .any(|note| note.starts_with("lg_tcache_max:12 is dropped")));
+    }
+
+    #[test]
+    fn rejects_genes_out_of_range() {
+        let schema = schema();
+        let err = schema.decode(&[1, 11, 4, 0]).unwrap_err();
+        assert!(err.contains("column 3"), "{err}");
+        assert!(err.contains("dirty_decay_ms"), "{err}");
+        let err = schema.decode_at(&[1, 11, 0, 3], 5).unwrap_err();
+        assert!(err.contains("column 8 is out of range for thp"), "{err}");
+    }
+
+    #[test]
+    fn rejects_the_wrong_number_of_genes() {
+        let err = schema().decode(&[1, 11, 0]).unwrap_err();
+        assert!(err.contains("expected 4 genes"), "{err}");
+    }
+
+    #[test]
+    fn rejects_conditions_on_unknown_options() {
+        let err = Schema::parse(
+            r#"
+            [[option]]
+            name = "tcache"
+            type = "bool"
+            requires = { narenas = [1] }
+            "#,
+        )
+        .unwrap_err();
+        assert!(err.contains("narenas"), "{err}");
+    }
+}
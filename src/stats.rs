@@ -0,0 +1,262 @@
+use clap::ValueEnum;
+use std::fmt;
+
+/// How the samples from repeated trials are turned into a single fitness.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Aggregate {
+    Median,
+    Mean,
+    P90,
+    /// The mean plus `k` standard deviations, penalising noisy confs.
+    MeanStddev,
+}
+
+/// Two sided 95% Student t critical values for 1 to 30 degrees of freedom.
+const T_95: [f64; 30] = [
+    12.706, 4.303, 3.182, 2.776, 2.571, 2.447, 2.365, 2.306, 2.262, 2.228, 2.201, 2.179, 2.160,
+    2.145, 2.131, 2.120, 2.110, 2.101, 2.093, 2.086, 2.080, 2.074, 2.069, 2.064, 2.060, 2.056,
+    2.052, 2.048, 2.045, 2.042,
+];
+
+/// The distribution of a set of samples.
+#[derive(Debug, Clone)]
+pub struct Summary {
+    pub count: usize,
+    pub mean: f64,
+    pub stddev: f64,
+    pub median: f64,
+    pub p90: f64,
+    pub min: f64,
+    pub max: f64,
+}
+
+impl Summary {
+    pub fn new(samples: &[usize]) -> Option<Self> {
+        if samples.is_empty() {
+            return None;
+        }
+
+        let mut sorted = samples.iter().map(|s| *s as f64).collect::<Vec<_>>();
+        sorted.sort_by(f64::total_cmp);
+
+        let count = sorted.len();
+        let mean = sorted.iter().sum::<f64>() / count as f64;
+        let stddev = if count > 1 {
+            (sorted.iter().map(|s| (s - mean).powi(2)).sum::<f64>() / (count - 1) as f64).sqrt()
+        } else {
+            0.0
+        };
+
+        Some(Self {
+            count,
+            mean,
+            stddev,
+            median: percentile(&sorted, 0.5),
+            p90: percentile(&sorted, 0.9),
+            min: sorted[0],
+            max: sorted[count - 1],
+        })
+    }
+
+    pub fn aggregate(&self, aggregate: Aggregate, stddevs: f64) -> f64 {
+        match aggregate {
+            Aggregate::Median => self.median,
+            Aggregate::Mean => self.mean,
+            Aggregate::P90 => self.p90,
+            Aggregate::MeanStddev => self.mean + stddevs * self.stddev,
+        }
+    }
+
+    /// The 95% confidence interval of the mean.
+    pub fn confidence_interval(&self) -> (f64, f64) {
+        if self.count < 2 {
+            return (self.mean, self.mean);
+        }
+
+        let t = T_95.get(self.count - 2).copied().unwrap_or(1.96);
+        let margin = t * self.stddev / (self.count as f64).sqrt();
+        (self.mean - margin, self.mean + margin)
+    }
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (low, high) = self.confidence_interval();
+        write!(
+            f,
+            "n={} mean={:.0} (95% CI {:.0}..{:.0}) stddev={:.0} median={:.0} p90={:.0} min={:.0} max={:.0}",
+            self.count, self.mean, low, high, self.stddev, self.median, self.p90, self.min, self.max
+        )
+    }
+}
+
+/// Linearly interpolated percentile of already sorted samples.
+fn percentile(sorted: &[f64], p: f64) -> f64 {
+    let rank = p * (sorted.len() - 1) as f64;
+    let (lower, upper) = (rank.floor() as usize, rank.ceil() as usize);
+    sorted[lower] + (sorted[upper] - sorted[lower]) * (rank - lower as f64)
+}
+
+/// How a time series of memory readings from one run becomes one sample.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum Metric {
+    /// The reading at the end of the run.
+    Final,
+    Peak,
+    /// The time weighted average over the whole run.
+    Average,
+    /// The time weighted average after the warm up.
+    Steady,
+}
+
+/// Reduce `(elapsed seconds, value)` points, in time order, to one value.
+pub fn reduce(metric: Metric, points: &[(f64, f64)], warmup: f64) -> f64 {
+    match metric {
+        Metric::Final => points.last().map_or(0.0, |(_, value)| *value),
+        Metric::Peak => points.iter().map(|(_, value)| *value).fold(0.0, f64::max),
+        Metric::Average => time_weighted_average(points),
+        Metric::Steady => {
+            let steady = points
+                .iter()
+                .copied()
+                .filter(|(elapsed, _)| *elapsed >= warmup)
+                .collect::<Vec<_>>();
+            if steady.is_empty() {
+                reduce(Metric::Final, points, warmup)
+            } else {
+                time_weighted_average(&steady)
+            }
+        }
+    }
+}
+
+/// Trapezoidal average, falling back to the plain mean when all the points
+/// are at the same time.
+fn time_weighted_average(points: &[(f64, f64)]) -> f64 {
+    let span = match (points.first(), points.last()) {
+        (Some((first, _)), Some((last, _))) => last - first,
+        _ => return 0.0,
+    };
+
+    if span <= 0.0 {
+        return points.iter().map(|(_, value)| value).sum::<f64>() / points.len() as f64;
+    }
+
+    points
+        .windows(2)
+        .map(|pair| (pair[1].0 - pair[0].0) * (pair[0].1 + pair[1].1) / 2.0)
+        .sum::<f64>()
+        / span
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(actual: f64, expected: f64) {
+        assert!(
+            (actual - expected).abs() < 1e-6,
+            "{actual} isn't {expected}"
+        );
+    }
+
+    #[test]
+    fn summarises_nothing_as_none() {
+        assert!(Summary::new(&[]).is_none());
+    }
+
+    #[test]
+    fn summarises_one_sample() {
+        let summary = Summary::new(&[100]).unwrap();
+        assert_eq!(summary.count, 1);
+        assert_eq!(summary.mean, 100.0);
+        assert_eq!(summary.stddev, 0.0);
+        assert_eq!(summary.median, 100.0);
+        assert_eq!(summary.p90, 100.0);
+        assert_eq!((summary.min, summary.max), (100.0, 100.0));
+        assert_eq!(summary.confidence_interval(), (100.0, 100.0));
+    }
+
+    #[test]
+    fn summarises_two_samples() {
+        let summary = Summary::new(&[200, 100]).unwrap();
+        assert_eq!(summary.mean, 150.0);
+        assert_close(summary.stddev, 5000f64.sqrt());
+        assert_eq!(summary.median, 150.0);
+        assert_close(summary.p90, 190.0);
+        assert_eq!((summary.min, summary.max), (100.0, 200.0));
+        // One degree of freedom, so t is 12.706 and the margin 12.706 * 50.
+        let (low, high) = summary.confidence_interval();
+        assert_close(low, 150.0 - 635.3);
+        assert_close(high, 150.0 + 635.3);
+    }
+
+    #[test]
+    fn takes_the_middle_of_an_even_number_of_samples() {
+        assert_eq!(Summary::new(&[4, 1, 3, 2]).unwrap().median, 2.5);
+        assert_eq!(Summary::new(&[3, 1, 2]).unwrap().median, 2.0);
+    }
+
+    #[test]
+    fn interpolates_the_p90() {
+        let samples = (1..=10).collect::<Vec<_>>();
+        assert_close(Summary::new(&samples).unwrap().p90, 9.1);
+        let samples = (1..=11).collect::<Vec<_>>();
+        assert_eq!(Summary::new(&samples).unwrap().p90, 10.0);
+    }
+
+    #[test]
+    fn uses_the_normal_interval_beyond_the_t_table() {
+        let samples = (0..40).map(|n| 100 + n % 2 * 10).collect::<Vec<_>>();
+        let summary = Summary::new(&samples).unwrap();
+        let (low, high) = summary.confidence_interval();
+        assert_close(high - summary.mean, 1.96 * summary.stddev / 40f64.sqrt());
+        assert_close(summary.mean - low, high - summary.mean);
+    }
+
+    #[test]
+    fn aggregates_the_mean_with_stddevs() {
+        let summary = Summary::new(&[100, 200]).unwrap();
+        assert_close(
+            summary.aggregate(Aggregate::MeanStddev, 2.0),
+            150.0 + 2.0 * 5000f64.sqrt(),
+        );
+        assert_eq!(summary.aggregate(Aggregate::MeanStddev, 0.0), 150.0);
+    }
+
+    const POINTS: [(f64, f64); 3] = [(0.0, 1000.0), (5.0, 100.0), (10.0, 100.0)];
+
+    #[test]
+    fn reduces_to_the_final_and_peak_readings() {
+        assert_eq!(reduce(Metric::Final, &POINTS, 0.0), 100.0);
+        assert_eq!(reduce(Metric::Peak, &POINTS, 0.0), 1000.0);
+        assert_eq!(reduce(Metric::Final, &[], 0.0), 0.0);
+    }
+
+    #[test]
+    fn weights_the_average_by_time() {
+        assert_eq!(reduce(Metric::Average, &POINTS, 0.0), 325.0);
+        // The plain mean would be about 67.
+        let uneven = [(0.0, 0.0), (1.0, 100.0), (10.0, 100.0)];
+        assert_eq!(reduce(Metric::Average, &uneven, 0.0), 95.0);
+    }
+
+    #[test]
+    fn averages_points_at_the_same_time() {
+        let points = [(3.0, 100.0), (3.0, 200.0)];
+        assert_eq!(reduce(Metric::Average, &points, 0.0), 150.0);
+        assert_eq!(reduce(Metric::Average, &[], 0.0), 0.0);
+    }
+
+    #[test]
+    fn leaves_out_the_warmup() {
+        assert_eq!(reduce(Metric::Steady, &POINTS, 5.0), 100.0);
+        assert_eq!(reduce(Metric::Steady, &POINTS, 4.9), 100.0);
+        assert_eq!(reduce(Metric::Steady, &POINTS, 0.0), 325.0);
+    }
+
+    #[test]
+    fn falls_back_to_the_final_reading_after_a_long_warmup() {
+        assert_eq!(reduce(Metric::Steady, &POINTS, 60.0), 100.0);
+    }
+}
@@ -0,0 +1,384 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    env,
+    fs::{self, File},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::OnceLock,
+    time::Duration,
+};
+use tokio::{
+    task::JoinSet,
+    time::{interval_at, timeout, Instant},
+};
+
+use crate::{
+    cache, cleanup,
+    conf::RunConf,
+    dogstatsd,
+    error::RunError,
+    experiment::{Experiment, ProcessSpec, TargetSpec},
+    load::{self, Generator},
+    memory::{MemoryStats, Snapshot},
+};
+
+/// How long a run may take beyond its length, to start up and be measured,
+/// before it's abandoned.
+const GRACE: Duration = Duration::from_secs(120);
+
+/// How many lines of the workload's output are kept when it crashes.
+pub const LOG_TAIL: usize = 20;
+
+/// Something jemopt can launch with a conf, load, and measure.
+pub trait Target {
+    /// A launched workload.
+    type Running;
+
+    /// Start the workload with jemalloc preloaded and the conf set, its
+    /// output appended to the log. Returns the workload and the local port
+    /// each of the workload's `ports`, like `8125/udp`, is reachable on.
+    async fn launch(
+        &self,
+        conf: &RunConf,
+        config: Option<&PathBuf>,
+        processes: &[ProcessSpec],
+        log: &Path,
+        ports: &[String],
+    ) -> Result<(Self::Running, HashMap<String, u16>), RunError>;
+
+    /// Measure the tracked processes. Fails if any required process is
+    /// missing.
+    async fn snapshot(
+        &self,
+        running: &Self::Running,
+        elapsed: f64,
+        processes: &[ProcessSpec],
+    ) -> Result<Snapshot, RunError>;
+
+    /// Why the workload crashed, if it has: it exited, or was killed for
+    /// running out of memory.
+    async fn crashed(&self, running: &mut Self::Running) -> Option<String>;
+
+    /// The last [`LOG_TAIL`] lines of the workload's output.
+    async fn logs(&self, running: &Self::Running) -> String;
+
+    /// Stop the workload. Failures are only reported, as the sample has
+    /// already been taken.
+    async fn stop(&self, running: Self::Running);
+}
+
+/// The `LD_PRELOAD` and `MALLOC_CONF` variables for the conf, which are
+/// empty without one so jemalloc isn't loaded at all.
+pub fn malloc_env(conf: &RunConf, preload: &[String]) -> Vec<(String, String)> {
+    if conf.is_empty() {
+        Vec::new()
+    } else {
+        vec![
+            ("LD_PRELOAD".to_string(), preload.join(":")),
+            ("MALLOC_CONF".to_string(), with_stats(&conf.global)),
+        ]
+    }
+}
+
+/// Bytes allocated between jemalloc's stats prints, when asked for with
+/// `--stats-interval`.
+static STATS_INTERVAL: OnceLock<u64> = OnceLock::new();
+
+/// Print jemalloc's stats every `bytes` allocated in every run from now on.
+pub fn set_stats_interval(bytes: u64) {
+    STATS_INTERVAL
+        .set(bytes)
+        .expect("the stats interval should only be set once");
+}
+
+/// The conf with jemalloc's stats printed on exit, so they end up in the
+/// run's log. Go binaries exit without running jemalloc's exit handler, so
+/// they only print with a stats interval, which leaves out the per arena
+/// and size class detail to keep the log short.
+pub fn with_stats(conf: &str) -> String {
+    let stats = match STATS_INTERVAL.get() {
+        Some(bytes) => {
+            format!("stats_print:true,stats_interval:{bytes},stats_interval_opts:gdablxeh")
+        }
+        None => "stats_print:true".to_string(),
+    };
+    if conf.is_empty() {
+        stats
+    } else {
+        format!("{conf},{stats}")
+    }
+}
+
+/// Create a new log for a run of the conf, at
+/// `<output>/<experiment hash>/<conf hash>-<n>.log`, numbered so trials
+/// don't overwrite each other.
+fn create_log(output: &Path, experiment: &Experiment, conf: &RunConf) -> io::Result<PathBuf> {
+    let dir = output.join(format!("{:016x}", experiment.hash));
+    fs::create_dir_all(&dir)?;
+    let conf_hash = cache::stable_hash(conf.to_string().as_bytes());
+    for n in 1.. {
+        let path = dir.join(format!("{conf_hash:016x}-{n}.log"));
+        match File::options().write(true).create_new(true).open(&path) {
+            Ok(mut file) => {
+                writeln!(file, "# jemopt conf {:?}", conf.to_string())?;
+                return Ok(path);
+            }
+            Err(err) if err.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(err) => return Err(err),
+        }
+    }
+    unreachable!()
+}
+
+/// Run the conf `trials` times, one after another, collecting a sample from
+/// every run that produced one, and the errors from those that didn't. Each
+/// run's log is archived under `output`.
+#[allow(clippy::too_many_arguments)]
+pub async fn run_trials(
+    experiment: &Experiment,
+    conf: &RunConf,
+    trials: usize,
+    seconds: u64,
+    payloads: bool,
+    config: Option<&str>,
+    interval: Duration,
+    output: &Path,
+) -> (Option<MemoryStats>, Vec<RunError>) {
+    let config = config.map(|c| {
+        env::current_dir()
+            .map(|cwd| cwd.join(c))
+            .expect("can get absolute path")
+    });
+
+    let mut stats: Option<MemoryStats> = None;
+    let mut errors = Vec::new();
+
+    for trial in 1..=trials {
+        let log = match create_log(output, experiment, conf) {
+            Ok(log) => log,
+            Err(err) => {
+                errors.push(RunError::Setup(format!(
+                    "failed to create a log in {}: {err}",
+                    output.display()
+                )));
+                break;
+            }
+        };
+        println!("Logging to {}", log.display());
+
+        let sample = match &experiment.target {
+            TargetSpec::Docker(target) => {
+                run(
+                    target,
+                    conf,
+                    seconds,
+                    payloads,
+                    config.as_ref(),
+                    interval,
+                    experiment,
+                    &log,
+                )
+                .await
+            }
+            TargetSpec::Process(target) => {
+                run(
+                    target,
+                    conf,
+                    seconds,
+                    payloads,
+                    config.as_ref(),
+                    interval,
+                    experiment,
+                    &log,
+                )
+                .await
+            }
+            TargetSpec::Command(target) => {
+                run(
+                    target,
+                    conf,
+                    seconds,
+                    payloads,
+                    config.as_ref(),
+                    interval,
+                    experiment,
+                    &log,
+                )
+                .await
+            }
+        };
+
+        match (sample, &mut stats) {
+            (Ok(sample), Some(stats)) => stats.extend(&sample),
+            (Ok(sample), None) => stats = Some(sample),
+            (Err(err), _) => {
+                println!("Trial {trial}/{trials} failed: {err}");
+                // A crash condemns the conf, so the other trials are moot.
+                let crashed = matches!(err, RunError::Crashed { .. });
+                errors.push(err);
+                if crashed {
+                    break;
+                }
+            }
+        }
+    }
+
+    (stats, errors)
+}
+
+/// Run the target with the conf for `seconds`, sampling the memory every
+/// `interval` and once more at the end. A zero interval only samples at the
+/// end.
+#[allow(clippy::too_many_arguments)]
+async fn run<T: Target>(
+    target: &T,
+    conf: &RunConf,
+    seconds: u64,
+    payloads: bool,
+    config: Option<&PathBuf>,
+    interval: Duration,
+    experiment: &Experiment,
+    log: &Path,
+) -> Result<MemoryStats, RunError> {
+    // Dropping a timed out run drops its cleanup guards, tearing it down.
+    timeout(
+        Duration::from_secs(seconds) + GRACE,
+        run_to_end(
+            target, conf, seconds, payloads, config, interval, experiment, log,
+        ),
+    )
+    .await
+    .unwrap_or(Err(RunError::Timeout))
+}
+
+#[allow(clippy::too_many_arguments)]
+async fn run_to_end<T: Target>(
+    target: &T,
+    conf: &RunConf,
+    seconds: u64,
+    payloads: bool,
+    config: Option<&PathBuf>,
+    interval: Duration,
+    experiment: &Experiment,
+    log: &Path,
+) -> Result<MemoryStats, RunError> {
+    let processes = &experiment.processes[..];
+    // Load over a socket needs no port.
+    let socket = experiment.load.socket.is_some();
+    let dogstatsd = match socket {
+        true => None,
+        false => experiment
+            .target
+            .dogstatsd_port()
+            .map(|port| format!("{port}/udp")),
+    };
+    let ports = match payloads {
+        true if dogstatsd.is_none() && !socket && experiment.generators.is_empty() => {
+            return Err(RunError::Setup("the target takes no load".to_string()));
+        }
+        // Generators can share a port, such as OTLP metrics and traces.
+        true => dogstatsd
+            .iter()
+            .cloned()
+            .chain(experiment.generators.iter().filter_map(Generator::port))
+            .collect::<BTreeSet<_>>()
+            .into_iter()
+            .collect(),
+        false => Vec::new(),
+    };
+
+    let (mut running, published) = target.launch(conf, config, processes, log, &ports).await?;
+
+    let start = Instant::now();
+    let run_for = Duration::from_secs(seconds);
+    // Dropping the set on an early return stops the load.
+    let mut traffic = JoinSet::new();
+    traffic.spawn(tokio::time::sleep(run_for));
+    if payloads {
+        let port = dogstatsd.and_then(|port| published.get(&port).copied());
+        if port.is_some() || socket {
+            traffic.spawn(cleanup::caught(dogstatsd::spam(
+                port,
+                run_for,
+                experiment.load.clone(),
+            )));
+        }
+        for generator in &experiment.generators {
+            traffic.spawn(cleanup::caught(load::generate(
+                generator.clone(),
+                published.clone(),
+                run_for,
+            )));
+        }
+    }
+
+    let mut series = Vec::new();
+    if !interval.is_zero() {
+        let mut ticker = interval_at(start + interval, interval);
+        loop {
+            ticker.tick().await;
+            if start.elapsed() >= run_for {
+                break;
+            }
+            if let Some(reason) = target.crashed(&mut running).await {
+                return Err(crash(target, running, reason).await);
+            }
+            // Workloads can take a while to start all their processes, so
+            // early snapshots can be incomplete. Those are skipped.
+            if let Ok(snapshot) = target
+                .snapshot(&running, start.elapsed().as_secs_f64(), processes)
+                .await
+            {
+                if let Some(reason) = restarted(&snapshot, &series) {
+                    return Err(crash(target, running, reason).await);
+                }
+                series.push(snapshot);
+            }
+        }
+    }
+    while let Some(result) = traffic.join_next().await {
+        if let Err(err) = result {
+            target.stop(running).await;
+            return Err(RunError::MeasurementFailed(format!(
+                "sending the load failed: {err}"
+            )));
+        }
+    }
+
+    let snapshot = target
+        .snapshot(&running, start.elapsed().as_secs_f64(), processes)
+        .await;
+    // A crash is the better explanation for a failed snapshot.
+    let reason = match snapshot.as_ref().ok().and_then(|s| restarted(s, &series)) {
+        Some(reason) => Some(reason),
+        None => target.crashed(&mut running).await,
+    };
+    if let Some(reason) = reason {
+        return Err(crash(target, running, reason).await);
+    }
+
+    let memory = snapshot.map(|snapshot| {
+        println!("Memory {} \x1b[31m{:?}\x1b[0m", conf, snapshot);
+        series.push(snapshot);
+        MemoryStats::new(series)
+    });
+
+    target.stop(running).await;
+
+    memory
+}
+
+/// Which process restarted since the last snapshot, if any did.
+fn restarted(snapshot: &Snapshot, series: &[Snapshot]) -> Option<String> {
+    let previous = series.last()?;
+    snapshot
+        .restarted(previous)
+        .map(|process| format!("{process} restarted"))
+}
+
+/// Stop the crashed workload, keeping the tail of its logs.
+async fn crash<T: Target>(target: &T, running: T::Running, reason: String) -> RunError {
+    let logs = target.logs(&running).await;
+    target.stop(running).await;
+    RunError::Crashed { reason, logs }
+}